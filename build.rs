@@ -0,0 +1,28 @@
+use std::path::Path;
+
+//Frontend libraries bundled into the binary by ci/fetch_static.sh, keep in
+//sync with that script & VENDOR_CDN in src/site.rs
+const VENDOR_FILES: &[&str] = &[
+    "bootstrap.min.css",
+    "bootstrap.min.js",
+    "jquery.min.js",
+    "popper.min.js",
+];
+
+//Builds without the libraries still work, the site then loads them from CDNs
+fn main() {
+    let vendor = Path::new("embed/static/vendor");
+    println!("cargo:rerun-if-changed={}", vendor.display());
+
+    let missing: Vec<&str> = VENDOR_FILES
+        .iter()
+        .filter(|file| !vendor.join(file).is_file())
+        .cloned()
+        .collect();
+    if !missing.is_empty() {
+        println!(
+            "cargo:warning=Not bundling {}, guests will need internet. Run ci/fetch_static.sh to bundle them",
+            missing.join(", ")
+        );
+    }
+}
@@ -0,0 +1,36 @@
+#!/usr/bin/env sh
+#Downloads the frontend libraries into embed/static/vendor so they're bundled
+#into the binary and the site works without internet. Versions are pinned,
+#bump them here, in embed/templates/base.html & VENDOR_CDN in src/site.rs
+#together
+set -e
+
+VENDOR="$(dirname "$0")/../embed/static/vendor"
+mkdir -p "$VENDOR"
+
+#Downloads a file & checks it against its subresource integrity hash, every
+#file must have one
+fetch() {
+    if [ -z "$4" ]; then
+        echo "No checksum pinned for $2" >&2
+        exit 1
+    fi
+    echo "Fetching $2"
+    curl -sSfL -o "$VENDOR/$2.part" "$1"
+    actual="$(openssl dgst -"$3" -binary "$VENDOR/$2.part" | openssl base64 -A)"
+    if [ "$actual" != "$4" ]; then
+        rm -f "$VENDOR/$2.part"
+        echo "Checksum mismatch for $2" >&2
+        exit 1
+    fi
+    mv "$VENDOR/$2.part" "$VENDOR/$2"
+}
+
+fetch https://stackpath.bootstrapcdn.com/bootstrap/4.2.1/css/bootstrap.min.css bootstrap.min.css \
+    sha384 GJzZqFGwb1QTTN6wy59ffF1BuGJpLSa9DkKMp0DgiMDm4iYMj70gZWKYbI706tWS
+fetch https://stackpath.bootstrapcdn.com/bootstrap/4.2.1/js/bootstrap.min.js bootstrap.min.js \
+    sha384 B0UglyR+jN6CkvvICOB2joaf5I4l3gm9GU6Hc1og6Ls7i6U/mkkaduKaBhlAXv9k
+fetch https://code.jquery.com/jquery-3.4.0.min.js jquery.min.js \
+    sha256 BJeo0qm959uMBGb65z40ejJYGSgR7REI4+CW1fNKwOg=
+fetch https://cdnjs.cloudflare.com/ajax/libs/popper.js/1.14.6/umd/popper.min.js popper.min.js \
+    sha384 wHAiFfRlMFy6i5SRaxvfOCifBUQy1xHdJ/yoi7FRNXMRBu5WHdZYu1hA6ZOblgut
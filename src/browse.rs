@@ -0,0 +1,95 @@
+use serde_derive::{Deserialize, Serialize};
+
+//Rows shown on each page of songs or artists
+pub const PAGE_SIZE: usize = 50;
+
+//Entry in the A-Z jump bar. Names not starting with a letter are under "#"
+#[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone)]
+pub struct Letter {
+    pub letter: String,
+    pub empty: bool,
+}
+
+//One page of rows. Pages are counted from 1, there's always at least one
+#[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub page: usize,
+    pub pages: usize,
+    pub total: usize,
+}
+
+pub fn index_letter(name: &str) -> String {
+    match name.trim().chars().next() {
+        Some(c) if c.is_ascii_alphabetic() => c.to_ascii_uppercase().to_string(),
+        _ => String::from("#"),
+    }
+}
+
+//Jump bar letters, marking those no name starts with
+pub fn letters<'a>(names: impl Iterator<Item = &'a str>) -> Vec<Letter> {
+    let used: Vec<String> = names.map(index_letter).collect();
+    std::iter::once('#')
+        .chain('A'..='Z')
+        .map(|c| {
+            let letter = c.to_string();
+            Letter {
+                empty: !used.contains(&letter),
+                letter,
+            }
+        })
+        .collect()
+}
+
+//Every word of the query appears in one of the fields, ignoring case
+pub fn matches(query: &str, fields: &[&str]) -> bool {
+    let fields: Vec<String> = fields.iter().map(|field| field.to_lowercase()).collect();
+    query
+        .to_lowercase()
+        .split_whitespace()
+        .all(|word| fields.iter().any(|field| field.contains(word)))
+}
+
+//Pages past the end show the last page
+pub fn paginate<T>(items: Vec<T>, page: usize, per_page: usize) -> Page<T> {
+    let total = items.len();
+    let pages = total.div_ceil(per_page).max(1);
+    let page = page.max(1).min(pages);
+    let items = items
+        .into_iter()
+        .skip((page - 1) * per_page)
+        .take(per_page)
+        .collect();
+    Page {
+        items,
+        page,
+        pages,
+        total,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_browse() {
+        assert_eq!(index_letter("abba"), "A");
+        assert_eq!(index_letter("10cc"), "#");
+        let letters = letters(vec!["Queen", "2Pac"].into_iter());
+        assert_eq!(letters.len(), 27);
+        assert!(!letters[0].empty);
+        assert!(letters[1].empty);
+        assert!(!letters[17].empty);
+
+        assert!(matches("queen rhapsody", &["Bohemian Rhapsody", "Queen"]));
+        assert!(!matches("queen abba", &["Bohemian Rhapsody", "Queen"]));
+
+        let page = paginate((0..120).collect(), 5, 50);
+        assert_eq!(page.page, 3);
+        assert_eq!(page.pages, 3);
+        assert_eq!(page.total, 120);
+        assert_eq!(page.items, (100..120).collect::<Vec<_>>());
+        assert_eq!(paginate(Vec::<u8>::new(), 0, 50).pages, 1);
+    }
+}
@@ -1,37 +1,73 @@
-use crossbeam_channel::{bounded, Receiver, Sender};
-use karaoke::collection::Kfile;
-use lazy_static::lazy_static;
+use crossbeam_channel::Sender;
+use karaoke::{
+    queue::{QueueEntry, QueueError},
+    requests::Requested,
+};
 
-lazy_static! {
-    pub static ref WORKER_CHANNEL: (Sender<WorkerCommand>, Receiver<WorkerCommand>) = {
-        let (player_send, player_receive) = bounded(1);
-        (player_send, player_receive)
-    };
-    pub static ref PLAYER_CHANNEL: (Sender<PlayerCommand>, Receiver<PlayerCommand>) = {
-        let (player_send, player_receive) = bounded(1);
-        (player_send, player_receive)
-    };
-    pub static ref LIVE_CHANNEL: (Sender<LiveCommand>, Receiver<LiveCommand>) = {
-        let (live_send, live_receive) = bounded(1);
-        (live_send, live_receive)
-    };
-}
-
-#[derive(Eq, PartialEq, Debug)]
+#[derive(Debug)]
 pub enum WorkerCommand {
     Stop,
     Next,
-    PlayNow { kfile: Kfile },
+    PlayNow {
+        entry: QueueEntry,
+    },
     ClearQueue,
-    AddQueue { kfile: Kfile },
+    //Playback adjustments, passed on to the player
+    Pause {
+        paused: bool,
+    },
+    SetVolume {
+        volume: u8,
+    },
+    SetKey {
+        key: i8,
+    },
+    MoveQueue {
+        from: usize,
+        to: usize,
+    },
+    //Whether the song was queued, held for approval or turned away is sent back on reply.
+    //Approved songs, ie. added by an admin, skip approval mode. Forced songs are
+    //queued even if they're a repeat
+    AddQueue {
+        entry: QueueEntry,
+        approved: bool,
+        force: bool,
+        reply: Sender<Result<Requested, QueueError>>,
+    },
+    ApproveRequest {
+        id: u64,
+    },
+    RejectRequest {
+        id: u64,
+    },
+    //Edits a pending request or queued song
+    EditRequest {
+        id: u64,
+        singer: Option<String>,
+    },
+    //Removes a pending request or queued song. Guests can only cancel their
+    //own, a guest of None can cancel any. Whether it was found is sent back
+    CancelEntry {
+        id: u64,
+        guest: Option<u64>,
+        reply: Sender<bool>,
+    },
 }
 
+//Commands share one channel, so the player carries them out in the order sent
 #[derive(Eq, PartialEq, Debug)]
 pub enum PlayerCommand {
-    Play { kfile: Kfile },
-}
-
-#[derive(Eq, PartialEq, Debug)]
-pub enum LiveCommand {
-    Stop, //
+    //Play immediately, replacing any current song
+    Play { entry: QueueEntry },
+    //Queue was added to, start playing if idle
+    CheckQueue,
+    //End the current song, or a Play sent before it that hasn't started yet
+    Stop,
+    //Pause or resume the current song, ignored while idle
+    Pause { paused: bool },
+    //Percentage, up to 100. Carries over between songs
+    Volume { volume: u8 },
+    //Semitones up or down, up to player::MAX_KEY. Carries over between songs
+    Key { key: i8 },
 }
@@ -1,46 +1,56 @@
+use failure::format_err;
 use glob::glob;
 use id3::Tag;
-use karaoke::CONFIG;
+use karaoke::{config::Config, thumbnail};
 use lazy_static::lazy_static;
+use log::warn;
 use rayon::prelude::*;
+use rodio::Source;
 use rustbreak::{deser::Yaml, FileDatabase};
 use serde_derive::{Deserialize, Serialize};
 use std::{
     collections::{hash_map::DefaultHasher, HashMap, HashSet},
     default::Default,
+    fs::{metadata, File},
     hash::{Hash, Hasher},
-    path::PathBuf,
+    io::BufReader,
+    path::{Path, PathBuf},
     result::Result,
+    sync::Mutex,
 };
 
+pub type CollectionDB = FileDatabase<HashMap<u64, Kfile>, Yaml>;
+
+//Cdg streams are made of 24 byte packets, played at 300 packets per second
+const CDG_PACKET_SIZE: u64 = 24;
+const CDG_PACKETS_PER_SECOND: u64 = 300;
+
+//Audio & cdg durations further apart than this are flagged as mismatched
+const DURATION_TOLERANCE_MS: u64 = 5000;
+
+//Songs measured between saves, so progress is kept if the app is closed
+const MEASURE_BATCH: usize = 50;
+
 lazy_static! {
-    pub static ref COLLECTION: Collection = {
-        let collection = startup(CONFIG.no_collection_update);
-        match collection {
-            Ok(c) => c,
-            Err(e) => panic!("{}", e),
-        }
-    };
+    //Held while the db file is loaded & saved, durations are measured in the
+    //background while the collection may be refreshed
+    static ref DB_LOCK: Mutex<()> = Mutex::new(());
 }
 
-pub type CollectionDB = FileDatabase<HashMap<u64, Kfile>, Yaml>;
-
 pub trait Custom {
-    fn initialize(path: &PathBuf) -> Result<Box<Self>, failure::Error>;
-    fn refresh(&self, path: &PathBuf) -> Result<(), failure::Error>;
+    fn initialize(path: &Path) -> Result<Box<Self>, failure::Error>;
+    fn refresh(&self, path: &Path) -> Result<(), failure::Error>;
     fn get_collection(&self) -> Result<Collection, failure::Error>;
 }
 
 impl Custom for CollectionDB {
     //If file doesn't exist, create default. Load db from file.
-    fn initialize(path: &PathBuf) -> Result<Box<CollectionDB>, failure::Error> {
-        let mut db: CollectionDB;
-
+    fn initialize(path: &Path) -> Result<Box<CollectionDB>, failure::Error> {
         let mut db_path = path.to_path_buf();
         db_path.push("db.yaml");
 
         let exists = db_path.exists();
-        db = CollectionDB::from_path(db_path, HashMap::new())?;
+        let db = CollectionDB::from_path(db_path, HashMap::new())?;
         if !exists {
             db.save()?;
         }
@@ -49,20 +59,22 @@ impl Custom for CollectionDB {
         Ok(Box::new(db))
     }
 
-    fn refresh(&self, song_path: &PathBuf) -> Result<(), failure::Error> {
-        let cdg_files = all_cdg(&song_path);
+    fn refresh(&self, song_path: &Path) -> Result<(), failure::Error> {
+        let cdg_files = all_cdg(song_path);
         let valid = valid_cdg_mp3_paths(cdg_files);
 
         let mut existing_keys = Vec::new();
-        self.read(|db| {
-            for key in db.keys() {
-                existing_keys.push(key.clone());
-            }
-        })?;
+        self.read(|db| existing_keys.extend(db.keys()))?;
 
         let valid_kfiles = valid
             .par_iter()
-            .map(|path| Kfile::new(path))
+            .filter_map(|path| match Kfile::new(path) {
+                Ok(kfile) => Some(kfile),
+                Err(e) => {
+                    warn!("Skipping song: {}", e);
+                    None
+                }
+            })
             .collect::<Vec<Kfile>>();
 
         let missing_valid_keys_to_remove: Vec<u64> = existing_keys
@@ -72,7 +84,7 @@ impl Custom for CollectionDB {
                     .par_iter()
                     .map(|x| calculate_hash(&x))
                     .collect();
-                if valid_keys.contains(&k) {
+                if valid_keys.contains(k) {
                     None
                 } else {
                     Some(*k)
@@ -84,7 +96,10 @@ impl Custom for CollectionDB {
             .par_iter()
             .filter_map(|k| {
                 if !existing_keys[..].contains(&calculate_hash(&k)) {
-                    Some(k.clone())
+                    //Audio is measured later, see measure_durations
+                    let mut kfile = k.clone();
+                    kfile.cdg_duration = cdg_duration(&kfile.cdg_path).ok();
+                    Some(kfile)
                 } else {
                     None
                 }
@@ -128,15 +143,75 @@ impl Custom for CollectionDB {
     }
 }
 
-pub fn startup(no_collection_update: bool) -> Result<Collection, failure::Error> {
-    let collection_db = CollectionDB::initialize(&CONFIG.data_path)?;
-    if !no_collection_update {
-        collection_db.refresh(&CONFIG.song_path)?;
+//Loads collection from db, scanning song path for changes first unless
+//disabled in config
+pub fn startup(config: &Config) -> Result<Collection, failure::Error> {
+    load(config, !config.no_collection_update)
+}
+
+pub fn load(config: &Config, refresh: bool) -> Result<Collection, failure::Error> {
+    let lock = DB_LOCK.lock().unwrap();
+    let collection_db = CollectionDB::initialize(&config.data_path)?;
+    if refresh {
+        collection_db.refresh(&config.song_path)?;
+    }
+    let collection = collection_db.get_collection()?;
+    drop(lock);
+    if refresh {
+        thumbnail::refresh(&collection, &config.data_path)?;
     }
-    collection_db.get_collection()
+    Ok(collection)
 }
 
-fn all_cdg(song_path: &PathBuf) -> Vec<PathBuf> {
+//Decodes the audio of songs without a duration, ie. new songs or those from a
+//db made before durations were stored. Done a batch at a time, each is saved
+//then passed to measured. Slow for big collections, so run after startup, see
+//Context::measure_durations. Returns how many songs were measured
+pub fn measure_durations(
+    config: &Config,
+    mut measured: impl FnMut(&[(u64, Kfile)]),
+) -> Result<usize, failure::Error> {
+    let mut count = 0;
+    loop {
+        let lock = DB_LOCK.lock().unwrap();
+        let collection_db = CollectionDB::initialize(&config.data_path)?;
+        let mut batch: Vec<(u64, Kfile)> = collection_db.read(|db| {
+            db.iter()
+                .filter(|(_, kfile)| kfile.duration.is_none() && !kfile.duration_mismatch)
+                .take(MEASURE_BATCH)
+                .map(|(key, kfile)| (*key, kfile.clone()))
+                .collect()
+        })?;
+        drop(lock);
+        if batch.is_empty() {
+            return Ok(count);
+        }
+
+        for (_, kfile) in batch.iter_mut() {
+            kfile.measure_duration();
+            if kfile.duration_mismatch {
+                warn!("Possibly corrupt, duration mismatch: {:?}", kfile.cdg_path);
+            }
+        }
+
+        //Songs removed by a refresh in the meantime stay removed
+        let lock = DB_LOCK.lock().unwrap();
+        let collection_db = CollectionDB::initialize(&config.data_path)?;
+        collection_db.write(|db| {
+            for (key, kfile) in &batch {
+                if let Some(song) = db.get_mut(key) {
+                    *song = kfile.clone();
+                }
+            }
+        })?;
+        collection_db.save()?;
+        drop(lock);
+        count += batch.len();
+        measured(&batch);
+    }
+}
+
+fn all_cdg(song_path: &Path) -> Vec<PathBuf> {
     let mut vec = Vec::new();
     let mut glob_path = song_path.to_path_buf();
     glob_path.push("**/*.cdg");
@@ -159,14 +234,14 @@ fn valid_cdg_mp3_paths(paths: Vec<PathBuf>) -> Vec<PathBuf> {
     valid
 }
 
-#[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone)]
+#[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone, Default)]
 pub struct Collection {
     pub by_song: HashMap<u64, Kfile>,
     pub by_artist: HashMap<u64, Artist>,
 }
 
 impl Collection {
-    fn new(vec_kfile: Vec<Kfile>) -> Collection {
+    pub fn new(vec_kfile: Vec<Kfile>) -> Collection {
         let mut by_song = HashMap::new();
         let mut by_artist = HashMap::new();
 
@@ -188,7 +263,7 @@ impl Collection {
         for (artist_hash, artist) in by_artist.iter_mut() {
             for (kfile_hash, kfile) in by_song.iter() {
                 if kfile.artist_hash == *artist_hash {
-                    artist.songs.insert(kfile_hash.clone(), kfile.clone());
+                    artist.songs.insert(*kfile_hash, kfile.clone());
                 }
             }
             let num_songs = artist.songs.len();
@@ -197,6 +272,19 @@ impl Collection {
 
         Collection { by_song, by_artist }
     }
+
+    //Replaces a song's details, ie. once measured. Songs no longer in the
+    //collection are left out
+    pub fn update_song(&mut self, id: u64, kfile: &Kfile) {
+        if let Some(song) = self.by_song.get_mut(&id) {
+            *song = kfile.clone();
+        }
+        if let Some(artist) = self.by_artist.get_mut(&kfile.artist_hash) {
+            if let Some(song) = artist.songs.get_mut(&id) {
+                *song = kfile.clone();
+            }
+        }
+    }
 }
 
 #[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone)]
@@ -217,20 +305,62 @@ impl Artist {
     }
 }
 
-#[derive(Hash, Eq, PartialEq, Debug, Serialize, Deserialize, Clone)]
+#[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone)]
 pub struct Kfile {
     pub mp3_path: PathBuf,
     pub cdg_path: PathBuf,
     pub artist: String,
     pub artist_hash: u64,
     pub song: String,
+    //Durations in milliseconds, of the audio and cdg streams respectively
+    #[serde(default)]
+    pub duration: Option<u64>,
+    #[serde(default)]
+    pub cdg_duration: Option<u64>,
+    #[serde(default)]
+    pub duration_mismatch: bool,
+}
+
+//Durations are left out of the hash, so a song's key stays the same once
+//it has been measured
+impl Hash for Kfile {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.mp3_path.hash(state);
+        self.cdg_path.hash(state);
+        self.artist.hash(state);
+        self.artist_hash.hash(state);
+        self.song.hash(state);
+    }
 }
 
 impl Kfile {
-    fn new(path: &PathBuf) -> Kfile {
-        let mp3_path = PathBuf::from(path.to_str().unwrap().to_string() + ".mp3");
-        let cdg_path = PathBuf::from(path.to_str().unwrap().to_string() + ".cdg");
-        let file_name = path.file_name().unwrap().to_str().unwrap();
+    /// Song from its path without extension, ie. `songs/Album - Artist - Song`
+    /// for `Album - Artist - Song.cdg` & `.mp3`. Artist & song come from the
+    /// mp3's tags, falling back to the file name, see [`song_parse`].
+    /// Names that aren't valid UTF-8 are parsed lossily, the original path is
+    /// kept for playback. Durations are left to [`Kfile::measure_duration`].
+    ///
+    /// ```
+    /// use karaoke::collection::Kfile;
+    /// use std::path::Path;
+    ///
+    /// let kfile = Kfile::new(Path::new("songs/Album - Artist - Mr. Song")).unwrap();
+    /// assert_eq!(kfile.artist, "Artist");
+    /// assert_eq!(kfile.song, "Mr. Song");
+    /// assert_eq!(kfile.cdg_path, Path::new("songs/Album - Artist - Mr. Song.cdg"));
+    /// ```
+    pub fn new(path: &Path) -> Result<Kfile, failure::Error> {
+        let mut mp3_path = path.as_os_str().to_os_string();
+        mp3_path.push(".mp3");
+        let mp3_path = PathBuf::from(mp3_path);
+        let mut cdg_path = path.as_os_str().to_os_string();
+        cdg_path.push(".cdg");
+        let cdg_path = PathBuf::from(cdg_path);
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| format_err!("Song path has no file name: {:?}", path))?
+            .to_string_lossy();
+        let file_name = file_name.as_ref();
 
         let tag = Tag::read_from_path(&mp3_path).unwrap_or_default();
         let tag_artist = tag.artist();
@@ -253,16 +383,57 @@ impl Kfile {
 
         let artist_hash = calculate_hash(&artist);
 
-        Kfile {
+        Ok(Kfile {
             mp3_path,
             cdg_path,
             artist: artist.to_string(),
             artist_hash,
             song: song.to_string(),
-        }
+            duration: None,
+            cdg_duration: None,
+            duration_mismatch: false,
+        })
+    }
+
+    //Reads the length of both streams. Songs whose audio can't be decoded, or
+    //whose lengths are far apart, are flagged as mismatched
+    pub fn measure_duration(&mut self) {
+        self.duration = audio_duration(&self.mp3_path).ok();
+        self.cdg_duration = cdg_duration(&self.cdg_path).ok();
+        self.duration_mismatch = match (self.duration, self.cdg_duration) {
+            (Some(audio), Some(cdg)) => audio.max(cdg) - audio.min(cdg) > DURATION_TOLERANCE_MS,
+            _ => true,
+        };
+    }
+
+    //Key the song is stored under in the collection
+    pub fn id(&self) -> u64 {
+        calculate_hash(self)
+    }
+
+    //Audio duration, or cdg duration if audio couldn't be measured
+    pub fn expected_duration(&self) -> Option<u64> {
+        self.duration.or(self.cdg_duration)
     }
 }
 
+//Decodes the entire mp3, as frame headers can't be trusted for variable bitrate files
+fn audio_duration(mp3_path: &Path) -> Result<u64, failure::Error> {
+    let file = File::open(mp3_path)?;
+    let decoder = rodio::Decoder::new(BufReader::new(file))?;
+    let samples_per_second = u64::from(decoder.channels()) * u64::from(decoder.sample_rate());
+    if samples_per_second == 0 {
+        failure::bail!("Invalid audio stream: {:?}", mp3_path);
+    }
+    let samples = decoder.count() as u64;
+    Ok(samples * 1000 / samples_per_second)
+}
+
+fn cdg_duration(cdg_path: &Path) -> Result<u64, failure::Error> {
+    let packets = metadata(cdg_path)?.len() / CDG_PACKET_SIZE;
+    Ok(packets * 1000 / CDG_PACKETS_PER_SECOND)
+}
+
 impl Default for Kfile {
     fn default() -> Kfile {
         Kfile {
@@ -271,12 +442,23 @@ impl Default for Kfile {
             artist: String::from(""),
             artist_hash: calculate_hash(&String::from("")),
             song: String::from(""),
+            duration: None,
+            cdg_duration: None,
+            duration_mismatch: false,
         }
     }
 }
 
-//Parses artist & song from files with naming convention: "album - artist - song"
-fn song_parse(file_name: &str) -> Option<(&str, &str)> {
+/// Parses artist & song from file names following the convention
+/// `Album - Artist - Song`, None for any other name.
+///
+/// ```
+/// use karaoke::collection::song_parse;
+///
+/// assert_eq!(song_parse("Album - Artist - Song"), Some(("Artist", "Song")));
+/// assert_eq!(song_parse("Artist - Song"), None);
+/// ```
+pub fn song_parse(file_name: &str) -> Option<(&str, &str)> {
     let mut split: Vec<&str> = file_name.split(" - ").collect();
 
     if split.len() == 3 {
@@ -288,7 +470,7 @@ fn song_parse(file_name: &str) -> Option<(&str, &str)> {
     }
 }
 
-fn calculate_hash<T: Hash>(t: &T) -> u64 {
+pub(crate) fn calculate_hash<T: Hash>(t: &T) -> u64 {
     let mut s = DefaultHasher::new();
     t.hash(&mut s);
     s.finish()
@@ -297,8 +479,10 @@ fn calculate_hash<T: Hash>(t: &T) -> u64 {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use karaoke::config::Config;
-    use std::{fs::remove_file, path::PathBuf};
+    use std::{
+        fs::{remove_file, write},
+        path::PathBuf,
+    };
 
     #[test]
     fn test_all_cdg() {
@@ -334,17 +518,56 @@ mod tests {
     #[test]
     fn test_kfile_new() {
         let path = PathBuf::from("ABCD001 - The Testers - Testing 123");
-        let kfile = Kfile::new(&path);
+        let kfile = Kfile::new(&path).unwrap();
         let _kfile = Kfile {
             mp3_path: PathBuf::from("ABCD001 - The Testers - Testing 123.mp3"),
             cdg_path: PathBuf::from("ABCD001 - The Testers - Testing 123.cdg"),
             artist: String::from("The Testers"),
             artist_hash: calculate_hash(&String::from("The Testers")),
             song: String::from("Testing 123"),
+            duration: None,
+            cdg_duration: None,
+            duration_mismatch: false,
         };
         assert_eq!(kfile, _kfile);
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn test_kfile_new_non_utf8() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let path = PathBuf::from(OsStr::from_bytes(b"ABCD001 - The Testers - Caf\xe9"));
+        let kfile = Kfile::new(&path).unwrap();
+        assert_eq!(kfile.song, "Caf\u{FFFD}");
+        assert_eq!(
+            kfile.cdg_path.as_os_str().as_bytes(),
+            &b"ABCD001 - The Testers - Caf\xe9.cdg"[..]
+        );
+    }
+
+    #[test]
+    fn test_cdg_duration() {
+        let cdg_path = PathBuf::from("tests/test_data/duration.cdg");
+        write(&cdg_path, vec![0u8; 24 * 300 * 3]).unwrap();
+        assert_eq!(cdg_duration(&cdg_path).unwrap(), 3000);
+        remove_file(&cdg_path).unwrap();
+    }
+
+    #[test]
+    fn test_measure_duration() {
+        let path = PathBuf::from("tests/test_data/songs/match");
+        let mut kfile = Kfile::new(&path).unwrap();
+        let key = calculate_hash(&kfile);
+        kfile.measure_duration();
+
+        //Empty mp3 can't be decoded, so pair is flagged
+        assert_eq!(kfile.duration, None);
+        assert_eq!(kfile.cdg_duration, Some(0));
+        assert!(kfile.duration_mismatch);
+        assert_eq!(calculate_hash(&kfile), key);
+    }
+
     #[test]
     fn test_startup() {
         let song_path = PathBuf::from("tests/test_data/songs");
@@ -353,6 +576,8 @@ mod tests {
             song_path: song_path.to_path_buf(),
             data_path: data_path.to_path_buf(),
             no_collection_update: false,
+            changeover_gap: 30,
+            ..Config::default()
         };
         let initialize = CollectionDB::initialize(&config.data_path);
         assert!(initialize.is_ok());
@@ -363,4 +588,32 @@ mod tests {
 
         remove_file("tests/test_data/db.yaml").unwrap();
     }
+
+    #[test]
+    fn test_measure_durations() {
+        let data_path = PathBuf::from("tests/test_data/measure");
+        let config = Config {
+            song_path: PathBuf::from("tests/test_data/songs"),
+            data_path: data_path.clone(),
+            ..Config::default()
+        };
+        std::fs::create_dir_all(&data_path).unwrap();
+        let collection = load(&config, true).unwrap();
+        assert_eq!(collection.by_song.len(), 2);
+        assert!(collection
+            .by_song
+            .values()
+            .all(|kfile| kfile.duration.is_none()));
+
+        let mut batches = Vec::new();
+        let count = measure_durations(&config, |batch| batches.push(batch.to_vec())).unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(batches.len(), 1);
+
+        //Flagged songs aren't measured again
+        let count = measure_durations(&config, |_| {}).unwrap();
+        assert_eq!(count, 0);
+
+        std::fs::remove_dir_all(&data_path).unwrap();
+    }
 }
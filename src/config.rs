@@ -1,12 +1,11 @@
 extern crate config as cfg;
 
 use dirs::{config_dir, data_dir};
-use karaoke::embed::create_config_if_not_exists;
+use karaoke::{embed::create_config_if_not_exists, theme::PlayerTheme};
 use lazy_static::lazy_static;
 use serde_derive::{Deserialize, Serialize};
 use std::{default::Default, fs::DirBuilder, path::PathBuf};
 
-
 //Default locations, overriden if supplied in Config file or by Argument
 lazy_static! {
     pub static ref CONF_FILE: PathBuf = {
@@ -37,11 +36,20 @@ lazy_static! {
     };
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Config {
     pub song_path: PathBuf,
     pub data_path: PathBuf,
     pub no_collection_update: bool,
+    pub changeover_gap: u64,
+    pub top_windows: Vec<u64>,
+    pub repeat_window: u64,
+    pub singer_limit: usize,
+    pub queue_limit: usize,
+    pub request_cooldown: u64,
+    pub approval_mode: bool,
+    pub admin_password: String,
+    pub player: PlayerTheme,
 }
 
 impl Default for Config {
@@ -50,6 +58,15 @@ impl Default for Config {
             song_path: SONG_DIR.to_path_buf(),
             data_path: DATA_DIR.to_path_buf(),
             no_collection_update: false,
+            changeover_gap: 30,
+            top_windows: vec![1, 7, 30],
+            repeat_window: 60,
+            singer_limit: 0,
+            queue_limit: 0,
+            request_cooldown: 0,
+            approval_mode: false,
+            admin_password: String::new(),
+            player: PlayerTheme::default(),
         }
     }
 }
@@ -81,15 +98,10 @@ pub fn load_config(
     no_collection_update: Option<bool>,
 ) -> Result<Config, failure::Error> {
     //If config_path supplied (from Arg), use that over default location
-    let config_file: PathBuf;
-    match config_path {
-        Some(path) => {
-            config_file = path.to_path_buf();
-        }
-        None => {
-            config_file = CONF_FILE.to_path_buf();
-        }
-    }
+    let config_file = match config_path {
+        Some(path) => path,
+        None => CONF_FILE.to_path_buf(),
+    };
     println!("Using config file: {:?}", config_file.display());
 
     //Write config template to path, if not exists
@@ -152,6 +164,15 @@ mod tests {
             song_path: PathBuf::from("test/test_data/songs"),
             data_path: PathBuf::from("test/test_data"),
             no_collection_update: true,
+            changeover_gap: 30,
+            top_windows: vec![1, 7, 30],
+            repeat_window: 60,
+            singer_limit: 0,
+            queue_limit: 0,
+            request_cooldown: 0,
+            approval_mode: false,
+            admin_password: String::new(),
+            player: PlayerTheme::default(),
         };
         assert_eq!(config, _config);
 
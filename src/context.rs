@@ -0,0 +1,273 @@
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender};
+use karaoke::{
+    channel::{PlayerCommand, WorkerCommand},
+    collection::{self, Collection},
+    config::Config,
+    events::{Broadcaster, Event},
+    history::History,
+    queue::{QueueEntry, QueueError},
+    requests::{Requested, Requests},
+    stats::Stats,
+    status::PlayerStatus,
+};
+use log::{error, info};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering::SeqCst},
+        Arc, Mutex, RwLock,
+    },
+    thread,
+    time::Duration,
+};
+
+//How long web requests wait on the worker before giving up
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+//State shared between the player, worker & site. Clones are cheap and all refer
+//to the same state, so each instance of the app should build exactly one
+#[derive(Debug, Clone)]
+pub struct Context {
+    pub config: Arc<Config>,
+    pub collection: Arc<RwLock<Collection>>,
+    pub queue: Arc<Mutex<Vec<QueueEntry>>>,
+    pub status: Arc<Mutex<PlayerStatus>>,
+    pub events: Arc<Mutex<Broadcaster>>,
+    pub history: Arc<Mutex<History>>,
+    pub stats: Arc<Mutex<Stats>>,
+    pub requests: Arc<Mutex<Requests>>,
+    //Whether song durations are being measured, see measure_durations
+    measuring: Arc<AtomicBool>,
+    //Whether the song path is being scanned, see spawn_refresh
+    refreshing: Arc<AtomicBool>,
+    pub worker_channel: (Sender<WorkerCommand>, Receiver<WorkerCommand>),
+    pub player_channel: (Sender<PlayerCommand>, Receiver<PlayerCommand>),
+}
+
+impl Context {
+    pub fn new(config: Config, collection: Collection, history: History, stats: Stats) -> Self {
+        Context {
+            config: Arc::from(config),
+            collection: Arc::from(RwLock::from(collection)),
+            queue: Arc::from(Mutex::from(Vec::new())),
+            status: Arc::from(Mutex::from(PlayerStatus::default())),
+            events: Arc::from(Mutex::from(Broadcaster::new())),
+            history: Arc::from(Mutex::from(history)),
+            stats: Arc::from(Mutex::from(stats)),
+            requests: Arc::from(Mutex::from(Requests::new())),
+            measuring: Arc::from(AtomicBool::new(false)),
+            refreshing: Arc::from(AtomicBool::new(false)),
+            worker_channel: unbounded(),
+            player_channel: unbounded(),
+        }
+    }
+
+    //Playback control, commands are carried out in order by the worker. Adding
+    //to the queue waits for the worker to check for repeats unless forced & the
+    //queue limits, and is held for approval in approval mode unless already
+    //approved. Errors if the worker doesn't answer
+    pub fn add_queue(
+        &self,
+        entry: QueueEntry,
+        approved: bool,
+        force: bool,
+    ) -> Result<Result<Requested, QueueError>, failure::Error> {
+        let (reply, result) = bounded(1);
+        self.send(WorkerCommand::AddQueue {
+            entry,
+            approved,
+            force,
+            reply,
+        });
+        wait_reply(&result)
+    }
+
+    pub fn play_now(&self, entry: QueueEntry) {
+        self.send(WorkerCommand::PlayNow { entry });
+    }
+
+    pub fn approve_request(&self, id: u64) {
+        self.send(WorkerCommand::ApproveRequest { id });
+    }
+
+    pub fn reject_request(&self, id: u64) {
+        self.send(WorkerCommand::RejectRequest { id });
+    }
+
+    pub fn edit_request(&self, id: u64, singer: Option<String>) {
+        self.send(WorkerCommand::EditRequest { id, singer });
+    }
+
+    pub fn cancel_entry(&self, id: u64, guest: Option<u64>) -> Result<bool, failure::Error> {
+        let (reply, result) = bounded(1);
+        self.send(WorkerCommand::CancelEntry { id, guest, reply });
+        wait_reply(&result)
+    }
+
+    //Moves the song at queue position from to position to
+    pub fn move_queue(&self, from: usize, to: usize) {
+        self.send(WorkerCommand::MoveQueue { from, to });
+    }
+
+    pub fn next(&self) {
+        self.send(WorkerCommand::Next);
+    }
+
+    pub fn clear_queue(&self) {
+        self.send(WorkerCommand::ClearQueue);
+    }
+
+    pub fn stop(&self) {
+        self.send(WorkerCommand::Stop);
+    }
+
+    pub fn pause(&self, paused: bool) {
+        self.send(WorkerCommand::Pause { paused });
+    }
+
+    pub fn set_volume(&self, volume: u8) {
+        self.send(WorkerCommand::SetVolume { volume });
+    }
+
+    pub fn set_key(&self, key: i8) {
+        self.send(WorkerCommand::SetKey { key });
+    }
+
+    fn send(&self, cmd: WorkerCommand) {
+        self.worker_channel.0.send(cmd).unwrap();
+    }
+
+    pub fn broadcast(&self, event: Event) {
+        self.events.lock().unwrap().send(&event);
+    }
+
+    //Swaps in a new collection, songs already queued are left alone
+    pub fn set_collection(&self, collection: Collection) {
+        let songs = collection.by_song.len();
+        *self.collection.write().unwrap() = collection;
+        self.broadcast(Event::Collection { songs });
+    }
+
+    //Scans song path for changes and swaps in the result
+    pub fn refresh_collection(&self) -> Result<(), failure::Error> {
+        let collection = collection::load(&self.config, true)?;
+        self.set_collection(collection);
+        Ok(())
+    }
+
+    //Refreshes the collection on a background thread then measures new songs.
+    //False if a refresh is already running
+    pub fn spawn_refresh(&self) -> bool {
+        if self.refreshing.swap(true, SeqCst) {
+            return false;
+        }
+        let context = self.clone();
+        thread::spawn(move || {
+            match context.refresh_collection() {
+                Ok(()) => context.measure_durations(),
+                Err(e) => error!("Failed to refresh collection: {}", e),
+            }
+            context.refreshing.store(false, SeqCst);
+        });
+        true
+    }
+
+    //Measures the audio of songs without a duration on a background thread,
+    //updating the collection a batch at a time. Runs until none are left,
+    //including songs added by a refresh meanwhile, so one run at a time is enough
+    pub fn measure_durations(&self) {
+        if self.measuring.swap(true, SeqCst) {
+            return;
+        }
+        let context = self.clone();
+        thread::spawn(move || {
+            let measured = collection::measure_durations(&context.config, |batch| {
+                let mut collection = context.collection.write().unwrap();
+                for (id, kfile) in batch {
+                    collection.update_song(*id, kfile);
+                }
+            });
+            context.measuring.store(false, SeqCst);
+            match measured {
+                Ok(0) => {}
+                Ok(count) => info!("Song durations updated: {}", count),
+                Err(e) => error!("Failed to measure song durations: {}", e),
+            }
+        });
+    }
+}
+
+//Replies come from the worker thread, which may have died with the command unread
+fn wait_reply<T>(result: &Receiver<T>) -> Result<T, failure::Error> {
+    result
+        .recv_timeout(REPLY_TIMEOUT)
+        .map_err(|_| failure::err_msg("Worker didn't reply"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{Future, Stream};
+    use karaoke::collection::Kfile;
+
+    #[test]
+    fn test_set_collection() {
+        let context = Context::new(
+            Config::default(),
+            Collection::default(),
+            History::new(),
+            Stats::new(),
+        );
+        let receiver = context.events.lock().unwrap().new_client();
+
+        let kfile = Kfile {
+            song: String::from("Testing 123"),
+            ..Kfile::default()
+        };
+        context.set_collection(Collection::new(vec![kfile]));
+        assert_eq!(context.collection.read().unwrap().by_song.len(), 1);
+
+        let (event, _) = receiver.into_future().wait().ok().unwrap();
+        assert_eq!(event, Some(Event::Collection { songs: 1 }));
+    }
+
+    #[test]
+    fn test_spawn_refresh() {
+        let data_path = std::path::PathBuf::from("tests/test_data/refresh");
+        std::fs::create_dir_all(&data_path).unwrap();
+        let config = Config {
+            song_path: std::path::PathBuf::from("tests/test_data/songs"),
+            data_path: data_path.clone(),
+            ..Config::default()
+        };
+        let context = Context::new(config, Collection::default(), History::new(), Stats::new());
+        let receiver = context.events.lock().unwrap().new_client();
+
+        assert!(context.spawn_refresh());
+        let (event, _) = receiver.into_future().wait().ok().unwrap();
+        assert_eq!(event, Some(Event::Collection { songs: 2 }));
+        assert_eq!(context.collection.read().unwrap().by_song.len(), 2);
+
+        //Measuring starts before the refresh is marked done
+        while context.refreshing.load(SeqCst) || context.measuring.load(SeqCst) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        std::fs::remove_dir_all(&data_path).unwrap();
+    }
+
+    #[test]
+    fn test_worker_gone() {
+        let context = Context::new(
+            Config::default(),
+            Collection::default(),
+            History::new(),
+            Stats::new(),
+        );
+
+        //Worker that dies on its first command, dropping the reply
+        let receiver = context.worker_channel.1.clone();
+        std::thread::spawn(move || drop(receiver.recv()));
+        assert!(context
+            .add_queue(QueueEntry::default(), false, false)
+            .is_err());
+    }
+}
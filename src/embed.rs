@@ -1,8 +1,9 @@
-use karaoke::CONFIG;
+use log::{error, warn};
 use rust_embed::RustEmbed;
 use std::{
-    fs::{write, DirBuilder},
-    path::PathBuf,
+    collections::HashMap,
+    fs::{create_dir_all, read, read_to_string, write},
+    path::Path,
 };
 
 #[derive(RustEmbed)]
@@ -21,48 +22,143 @@ struct Config;
 #[folder = "assets"]
 pub struct Assets;
 
-pub fn unload_files() {
-    //Create templates folder in data path if not already exists
-    let mut templates_path = CONFIG.data_path.clone();
-    templates_path.push("templates");
-    if !templates_path.is_dir() {
-        DirBuilder::new()
-            .recursive(true)
-            .create(templates_path.clone())
-            .unwrap();
-    }
+//Hashes of embedded files as last written to the data dir. A file that no
+//longer matches was edited by the user and is left alone
+const MANIFEST: &str = "embedded.json";
+
+//Writes embedded templates & static files missing from the data dir, and
+//updates those unchanged since they were written
+pub fn unload_files(data_path: &Path) {
+    let manifest_path = data_path.join(MANIFEST);
+    let saved: Option<HashMap<String, u64>> = read_to_string(&manifest_path)
+        .ok()
+        .and_then(|manifest| serde_json::from_str(&manifest).ok());
+    //Versions before the manifest kept no hashes, so there's no telling which
+    //files were edited. Treat them all as shipped & update them
+    let keep_custom = saved.is_some();
+    let mut manifest = saved.unwrap_or_default();
 
-    //Save each file into static path
     for file in Templates::iter() {
         let file_data = Templates::get(file.as_ref()).unwrap();
-        let mut path = templates_path.clone();
-        path.push(file.as_ref());
-        write(path.as_path(), file_data.as_ref()).unwrap();
+        let name = format!("templates/{}", file);
+        unload_file(
+            data_path,
+            &name,
+            file_data.as_ref(),
+            keep_custom,
+            &mut manifest,
+        );
+    }
+    for file in Static::iter() {
+        let file_data = Static::get(file.as_ref()).unwrap();
+        let name = format!("static/{}", file);
+        unload_file(
+            data_path,
+            &name,
+            file_data.as_ref(),
+            keep_custom,
+            &mut manifest,
+        );
     }
 
-    //Create static folder in data path if not already exists
-    let mut static_path = CONFIG.data_path.clone();
-    static_path.push("static");
-    if !static_path.is_dir() {
-        DirBuilder::new()
-            .recursive(true)
-            .create(static_path.clone())
-            .unwrap();
+    let manifest = serde_json::to_string_pretty(&manifest).unwrap();
+    if let Err(e) = write(&manifest_path, manifest) {
+        error!("Failed to save {:?}: {}", manifest_path, e);
     }
+}
 
-    //Save each file into static path
-    for file in Static::iter() {
-        let file_data = Static::get(file.as_ref()).unwrap();
-        let mut path = static_path.clone();
-        path.push(file.as_ref());
-        write(path.as_path(), file_data.as_ref()).unwrap();
+fn unload_file(
+    data_path: &Path,
+    name: &str,
+    data: &[u8],
+    keep_custom: bool,
+    manifest: &mut HashMap<String, u64>,
+) {
+    let path = data_path.join(name);
+    let embedded = file_hash(data);
+    if let Ok(current) = read(&path) {
+        let current = file_hash(&current);
+        if current == embedded {
+            manifest.insert(name.to_string(), embedded);
+            return;
+        }
+        if keep_custom && manifest.get(name) != Some(&current) {
+            warn!(
+                "Keeping customized {:?}, delete it to get the latest version",
+                path
+            );
+            return;
+        }
+    }
+
+    if let Some(parent) = path.parent() {
+        if let Err(e) = create_dir_all(parent) {
+            error!("Failed to create {:?}: {}", parent, e);
+            return;
+        }
+    }
+    match write(&path, data) {
+        Ok(_) => {
+            manifest.insert(name.to_string(), embedded);
+        }
+        Err(e) => error!("Failed to write {:?}: {}", path, e),
     }
 }
 
-pub fn create_config_if_not_exists(config_path: &PathBuf) -> Result<(), failure::Error> {
+//FNV-1a, which unlike DefaultHasher is the same across Rust versions
+fn file_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+pub fn create_config_if_not_exists(config_path: &Path) -> Result<(), failure::Error> {
     if !config_path.exists() {
         let config = Config::get("config.yaml").unwrap();
-        write(config_path.as_path(), config.as_ref())?;
+        write(config_path, config.as_ref())?;
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs::remove_dir_all, path::PathBuf};
+
+    #[test]
+    fn test_unload_keeps_custom_files() {
+        let data_path = PathBuf::from("tests/test_data/embed");
+        let base = data_path.join("templates/base.html");
+        let index = data_path.join("templates/index.html");
+        unload_files(&data_path);
+        let shipped = read(&index).unwrap();
+
+        //Edited files are kept, missing ones are written again
+        write(&base, "custom").unwrap();
+        std::fs::remove_file(&index).unwrap();
+        unload_files(&data_path);
+        assert_eq!(read_to_string(&base).unwrap(), "custom");
+        assert_eq!(read(&index).unwrap(), shipped);
+
+        //Files still as an older version wrote them are updated
+        write(&index, "old").unwrap();
+        let mut manifest: HashMap<String, u64> =
+            serde_json::from_str(&read_to_string(data_path.join(MANIFEST)).unwrap()).unwrap();
+        manifest.insert(String::from("templates/index.html"), file_hash(b"old"));
+        write(
+            data_path.join(MANIFEST),
+            serde_json::to_string(&manifest).unwrap(),
+        )
+        .unwrap();
+        unload_files(&data_path);
+        assert_eq!(read(&index).unwrap(), shipped);
+
+        //Without a manifest nothing counts as customized
+        std::fs::remove_file(data_path.join(MANIFEST)).unwrap();
+        unload_files(&data_path);
+        let base_shipped = Templates::get("base.html").unwrap();
+        assert_eq!(read(&base).unwrap(), base_shipped.as_ref());
+
+        remove_dir_all(&data_path).unwrap();
+    }
+}
@@ -0,0 +1,77 @@
+use futures::sync::mpsc::{channel, Receiver, Sender};
+use karaoke::{queue::QueueEntry, requests::SongRequest, status::PlayerStatus};
+
+//Events buffered per client before it's considered stalled and dropped
+const CLIENT_BUFFER: usize = 64;
+
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum Event {
+    //Queue was added to, cleared, or advanced
+    Queue {
+        queue: Vec<QueueEntry>,
+        status: PlayerStatus,
+    },
+    //Song started or stopped
+    Status(PlayerStatus),
+    //Playback position of current song in milliseconds, sent about once a second
+    Position(u64),
+    //Collection was refreshed, with the number of songs it now holds
+    Collection {
+        songs: usize,
+    },
+    //Request was made, approved, rejected or edited in approval mode
+    Requests(Vec<SongRequest>),
+    //Song couldn't be played and was skipped
+    PlaybackError {
+        song: String,
+        message: String,
+    },
+}
+
+//Fans events out to every connected client
+#[derive(Debug, Default)]
+pub struct Broadcaster {
+    clients: Vec<Sender<Event>>,
+}
+
+impl Broadcaster {
+    pub fn new() -> Self {
+        Broadcaster::default()
+    }
+
+    pub fn new_client(&mut self) -> Receiver<Event> {
+        let (sender, receiver) = channel(CLIENT_BUFFER);
+        self.clients.push(sender);
+        receiver
+    }
+
+    //Clients that have disconnected, or fallen too far behind, are removed
+    pub fn send(&mut self, event: &Event) {
+        let clients = self.clients.drain(..).collect::<Vec<_>>();
+        for mut client in clients {
+            if client.try_send(event.clone()).is_ok() {
+                self.clients.push(client);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{Future, Stream};
+
+    #[test]
+    fn test_broadcast_drops_closed_clients() {
+        let mut broadcaster = Broadcaster::new();
+        let first = broadcaster.new_client();
+        let second = broadcaster.new_client();
+        drop(second);
+
+        broadcaster.send(&Event::Position(1000));
+        assert_eq!(broadcaster.clients.len(), 1);
+
+        let (event, _) = first.into_future().wait().ok().unwrap();
+        assert_eq!(event, Some(Event::Position(1000)));
+    }
+}
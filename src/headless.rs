@@ -0,0 +1,227 @@
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use image::{imageops, FilterType, GenericImage, Rgba, RgbaImage};
+use karaoke::{
+    output::{AudioOutput, VideoEvent, VideoOutput},
+    theme::Backdrop,
+};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering::SeqCst},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+//Offscreen copy of everything drawn, plus counters of how often each screen
+//was drawn and how often the cdg frame had to be copied
+pub struct Framebuffer {
+    pub image: RgbaImage,
+    pub frames: usize,
+    pub idle_frames: usize,
+    pub cdg_uploads: usize,
+    pub notice: Option<String>,
+}
+
+//Video output drawing into an in-memory Framebuffer instead of a window. Events
+//can be injected through event_sender to simulate keyboard input
+pub struct HeadlessVideo {
+    framebuffer: Arc<Mutex<Framebuffer>>,
+    //Last cdg frame copied, stands in for the glium output's texture
+    cdg: Option<RgbaImage>,
+    event_sender: Sender<VideoEvent>,
+    event_receiver: Receiver<VideoEvent>,
+}
+
+impl HeadlessVideo {
+    pub fn new(width: u32, height: u32) -> Self {
+        let framebuffer = Framebuffer {
+            image: RgbaImage::new(width, height),
+            frames: 0,
+            idle_frames: 0,
+            cdg_uploads: 0,
+            notice: None,
+        };
+        let (event_sender, event_receiver) = unbounded();
+        HeadlessVideo {
+            framebuffer: Arc::from(Mutex::from(framebuffer)),
+            cdg: None,
+            event_sender,
+            event_receiver,
+        }
+    }
+
+    pub fn framebuffer(&self) -> Arc<Mutex<Framebuffer>> {
+        self.framebuffer.clone()
+    }
+
+    pub fn event_sender(&self) -> Sender<VideoEvent> {
+        self.event_sender.clone()
+    }
+}
+
+impl VideoOutput for HeadlessVideo {
+    fn draw_idle(&mut self, notice: Option<&str>) -> Result<(), failure::Error> {
+        self.cdg = None;
+        let mut framebuffer = self.framebuffer.lock().unwrap();
+        fill(
+            &mut framebuffer.image,
+            Rgba {
+                data: [0, 0, 0, 255],
+            },
+        );
+        framebuffer.idle_frames += 1;
+        framebuffer.notice = notice.map(String::from);
+        Ok(())
+    }
+
+    //Cdg image is drawn unscaled in the center of the framebuffer, copied
+    //only when it's changed like the glium texture. Backdrop images are
+    //always stretched to fill it
+    fn draw_frame(
+        &mut self,
+        backdrop: Backdrop,
+        cdg: &RgbaImage,
+        cdg_changed: bool,
+    ) -> Result<(), failure::Error> {
+        let mut framebuffer = self.framebuffer.lock().unwrap();
+        let reusable = match &self.cdg {
+            Some(image) => image.dimensions() == cdg.dimensions(),
+            None => false,
+        };
+        if !reusable || cdg_changed {
+            self.cdg = Some(cdg.clone());
+            framebuffer.cdg_uploads += 1;
+        }
+        let cdg = self.cdg.as_ref().unwrap();
+
+        let (width, height) = framebuffer.image.dimensions();
+        match backdrop {
+            Backdrop::Color(color) => {
+                let color = Rgba {
+                    data: [
+                        (color.0 * 255.0) as u8,
+                        (color.1 * 255.0) as u8,
+                        (color.2 * 255.0) as u8,
+                        (color.3 * 255.0) as u8,
+                    ],
+                };
+                fill(&mut framebuffer.image, color);
+            }
+            Backdrop::Image { image, .. } => {
+                let image = imageops::resize(image, width, height, FilterType::Nearest);
+                framebuffer.image.copy_from(&image, 0, 0);
+            }
+        }
+
+        let (cdg_x, cdg_y) = cdg.dimensions();
+        let left = width.saturating_sub(cdg_x) / 2;
+        let top = height.saturating_sub(cdg_y) / 2;
+        framebuffer.image.copy_from(cdg, left, top);
+        framebuffer.frames += 1;
+        Ok(())
+    }
+
+    fn poll_events(&mut self) -> Vec<VideoEvent> {
+        self.event_receiver.try_iter().collect()
+    }
+}
+
+//Images don't implement Debug, only the size is shown
+impl fmt::Debug for Framebuffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Framebuffer")
+            .field("dimensions", &self.image.dimensions())
+            .field("frames", &self.frames)
+            .field("idle_frames", &self.idle_frames)
+            .field("cdg_uploads", &self.cdg_uploads)
+            .field("notice", &self.notice)
+            .finish()
+    }
+}
+
+fn fill(image: &mut RgbaImage, color: Rgba<u8>) {
+    for pixel in image.pixels_mut() {
+        *pixel = color;
+    }
+}
+
+//Clock which only moves when told to, so playback runs independent of wall time
+#[derive(Debug, Default, Clone)]
+pub struct VirtualClock {
+    millis: Arc<AtomicUsize>,
+}
+
+impl VirtualClock {
+    pub fn advance(&self, by: Duration) {
+        self.millis.fetch_add(by.as_millis() as usize, SeqCst);
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_millis(self.millis.load(SeqCst) as u64)
+    }
+
+    pub fn reset(&self) {
+        self.millis.store(0, SeqCst);
+    }
+}
+
+//Audio output that discards audio. Song position is read from the clock, which
+//starts over with every song and is driven through clock(). While paused the
+//position holds, picking up from there once resumed
+#[derive(Default)]
+pub struct NullAudio {
+    clock: VirtualClock,
+    played: Arc<Mutex<Vec<PathBuf>>>,
+    paused_at: Option<Duration>,
+}
+
+impl NullAudio {
+    pub fn new() -> Self {
+        NullAudio {
+            clock: VirtualClock::default(),
+            played: Arc::from(Mutex::from(Vec::new())),
+            paused_at: None,
+        }
+    }
+
+    pub fn clock(&self) -> VirtualClock {
+        self.clock.clone()
+    }
+
+    //Every path passed to play, in order
+    pub fn played(&self) -> Arc<Mutex<Vec<PathBuf>>> {
+        self.played.clone()
+    }
+}
+
+impl AudioOutput for NullAudio {
+    fn play(&mut self, path: &Path) -> Result<(), failure::Error> {
+        self.clock.reset();
+        self.paused_at = None;
+        self.played.lock().unwrap().push(path.to_path_buf());
+        Ok(())
+    }
+
+    fn position(&self) -> Duration {
+        self.paused_at.unwrap_or_else(|| self.clock.elapsed())
+    }
+
+    fn stop(&mut self) {}
+
+    fn pause(&mut self, paused: bool) {
+        match (paused, self.paused_at) {
+            (true, None) => self.paused_at = Some(self.clock.elapsed()),
+            (false, Some(at)) => {
+                self.clock.millis.store(at.as_millis() as usize, SeqCst);
+                self.paused_at = None;
+            }
+            _ => {}
+        }
+    }
+
+    fn set_volume(&mut self, _volume: u8) {}
+
+    fn set_key(&mut self, _key: i8) {}
+}
@@ -0,0 +1,154 @@
+use karaoke::status::PlayerStatus;
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+//One playback of a song. Started is a unix timestamp & played is how many
+//milliseconds of the song were heard before it ended or was skipped
+#[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone)]
+pub struct HistoryEntry {
+    pub song_id: u64,
+    pub artist: String,
+    pub song: String,
+    pub singer: Option<String>,
+    pub started: u64,
+    pub played: u64,
+    pub skipped: bool,
+}
+
+impl HistoryEntry {
+    //Entry for the song status is playing, None if nothing is
+    pub fn from_status(status: &PlayerStatus, skipped: bool) -> Option<Self> {
+        let kfile = status.kfile.as_ref()?;
+        Some(HistoryEntry {
+            song_id: kfile.id(),
+            artist: kfile.artist.clone(),
+            song: kfile.song.clone(),
+            singer: status.singer.clone(),
+            started: status.started.unwrap_or(0),
+            played: status.position,
+            skipped,
+        })
+    }
+}
+
+//Every song played, oldest first. Entries are appended to the history file one
+//JSON object per line, so a crash loses at most the song being played
+#[derive(Debug, Default)]
+pub struct History {
+    path: Option<PathBuf>,
+    entries: Vec<HistoryEntry>,
+}
+
+impl History {
+    //History that's only kept in memory
+    pub fn new() -> Self {
+        History::default()
+    }
+
+    //Loads history.jsonl from the data dir, creating it on the first recorded song
+    pub fn open(data_path: &Path) -> Result<Self, failure::Error> {
+        let path = data_path.join("history.jsonl");
+        let mut entries = Vec::new();
+
+        if path.exists() {
+            let reader = BufReader::new(File::open(&path)?);
+            for line in reader.lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str(&line) {
+                    Ok(entry) => entries.push(entry),
+                    Err(e) => warn!("Skipping unreadable history entry: {}", e),
+                }
+            }
+        }
+
+        Ok(History {
+            path: Some(path),
+            entries,
+        })
+    }
+
+    pub fn record(&mut self, entry: HistoryEntry) -> Result<(), failure::Error> {
+        if let Some(path) = &self.path {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+        }
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    pub fn entries(&self) -> &[HistoryEntry] {
+        &self.entries
+    }
+
+    //Most recently played first
+    pub fn recent(&self) -> Vec<HistoryEntry> {
+        self.entries.iter().rev().cloned().collect()
+    }
+
+    //Every entry with a header row, for royalty reporting
+    pub fn to_csv(&self) -> Result<String, failure::Error> {
+        let mut data = Vec::new();
+        {
+            let mut writer = csv::Writer::from_writer(&mut data);
+            for entry in &self.entries {
+                writer.serialize(entry)?;
+            }
+            writer.flush()?;
+        }
+        Ok(String::from_utf8(data)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use karaoke::{collection::Kfile, queue::QueueEntry};
+    use std::fs::remove_file;
+
+    #[test]
+    fn test_history_persists() {
+        let data_path = PathBuf::from("tests/test_data");
+        let _ = remove_file(data_path.join("history.jsonl"));
+
+        let mut status = PlayerStatus::default();
+        status.start(QueueEntry::new(
+            Kfile {
+                artist: String::from("Artist, The"),
+                song: String::from("Song"),
+                ..Kfile::default()
+            },
+            Some(String::from("Alice")),
+        ));
+        status.position = 42_000;
+        let entry = HistoryEntry::from_status(&status, true).unwrap();
+
+        let mut history = History::open(&data_path).unwrap();
+        history.record(entry.clone()).unwrap();
+        let history = History::open(&data_path).unwrap();
+        assert_eq!(history.entries(), std::slice::from_ref(&entry));
+
+        let csv = history.to_csv().unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(
+            lines.next(),
+            Some("song_id,artist,song,singer,started,played,skipped")
+        );
+        assert_eq!(
+            lines.next().unwrap(),
+            format!(
+                "{},\"Artist, The\",Song,Alice,{},42000,true",
+                entry.song_id, entry.started
+            )
+        );
+
+        remove_file(data_path.join("history.jsonl")).unwrap();
+    }
+}
@@ -0,0 +1,27 @@
+//! Karaoke engine behind the karaoke-rs player: collection scanning, the play
+//! queue, cdg playback and the web UI. Build a `context::Context`, then start
+//! whichever of `player`, `worker` & `site` are needed with it.
+
+extern crate self as karaoke;
+
+pub mod browse;
+pub mod channel;
+pub mod collection;
+pub mod config;
+pub mod context;
+pub mod embed;
+pub mod events;
+pub mod headless;
+pub mod history;
+pub mod output;
+pub mod overlay;
+pub mod player;
+pub mod queue;
+pub mod render;
+pub mod requests;
+pub mod site;
+pub mod stats;
+pub mod status;
+pub mod theme;
+pub mod thumbnail;
+pub mod worker;
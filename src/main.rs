@@ -1,44 +1,42 @@
-extern crate self as karaoke;
-
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches, SubCommand};
 use karaoke::{
-    collection::COLLECTION,
     config::{load_config, Config},
+    context::Context,
+    history::History,
+    player::SECTOR_OFFSET,
+    render::{kfile_from_inputs, render, song_file},
+    stats::Stats,
+};
+use std::{
+    fs::metadata,
+    path::{Path, PathBuf},
 };
-use lazy_static::lazy_static;
-use std::{fs::metadata, path::PathBuf};
 
-mod channel;
-mod collection;
-mod config;
-mod embed;
-mod player;
-mod queue;
-mod site;
-mod worker;
+fn main() -> Result<(), failure::Error> {
+    let matches = get_args();
+    if let Some(matches) = matches.subcommand_matches("render") {
+        return run_render(matches);
+    }
 
-lazy_static! {
-    pub static ref CONFIG: Config = {
-        let config = get_config();
-        match config {
-            Ok(c) => c,
-            Err(e) => panic!("{}", e),
-        }
-    };
-}
+    std::env::set_var("RUST_LOG", "actix_web=debug,karaoke=info");
+    env_logger::init();
 
-fn main() -> Result<(), failure::Error> {
-    lazy_static::initialize(&CONFIG);
-    lazy_static::initialize(&COLLECTION);
-    karaoke::embed::unload_files();
-    karaoke::player::run();
-    karaoke::worker::run();
-    karaoke::site::run()?;
+    let config = get_config(&matches)?;
+    let collection = karaoke::collection::startup(&config)?;
+    karaoke::embed::unload_files(&config.data_path);
+    let history = History::open(&config.data_path)?;
+    let stats = Stats::open(&config.data_path)?;
+
+    let context = Context::new(config, collection, history, stats);
+    context.measure_durations();
+    karaoke::player::run(&context);
+    karaoke::worker::run(&context);
+    karaoke::site::run(context)?;
     Ok(())
 }
 
-fn get_config() -> Result<Config, failure::Error> {
-    let matches = App::new("karoake-rs")
+fn get_args() -> ArgMatches<'static> {
+    App::new("karoake-rs")
         .version("0.6.1")
         .author("Cory F. <cforsstrom18@gmail.com>")
         .about("A simple, network enabled karaoke player in Rust")
@@ -71,28 +69,70 @@ fn get_config() -> Result<Config, failure::Error> {
                 .long("no-collection-update")
                 .help("Disable collection update on startup"),
         )
-        .get_matches();
-
-    let config_path: Option<PathBuf>;
-    let song_path: Option<PathBuf>;
-    let data_path: Option<PathBuf>;
+        .subcommand(
+            SubCommand::with_name("render")
+                .about("Renders a song to a video file")
+                .arg(
+                    Arg::with_name("input")
+                        .value_name("FILE")
+                        .help("Song path with or without extension, or a cdg and mp3 file")
+                        .required(true)
+                        .min_values(1)
+                        .max_values(2),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .value_name("FILE")
+                        .help("Sets the output video file, defaults to song name .mp4")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("fps")
+                        .short("f")
+                        .long("fps")
+                        .value_name("FPS")
+                        .help("Sets the output frame rate, defaults to 75")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("offset")
+                        .long("offset")
+                        .value_name("SECTORS")
+                        .help("Delays lyrics by number of cdg sectors (1/75th of a second), defaults to 20")
+                        .takes_value(true)
+                        .allow_hyphen_values(true),
+                ),
+        )
+        .get_matches()
+}
 
-    //Return each path if valid, panic if not
-    let _config_path = matches.value_of("config");
-    config_path = match _config_path {
-        Some(path) => validate_file(path),
-        None => None,
+fn run_render(matches: &ArgMatches) -> Result<(), failure::Error> {
+    let inputs: Vec<&str> = matches.values_of("input").unwrap().collect();
+    let kfile = kfile_from_inputs(&inputs)?;
+    let fps: u64 = matches.value_of("fps").unwrap_or("75").parse()?;
+    let offset: i64 = match matches.value_of("offset") {
+        Some(offset) => offset.parse()?,
+        None => SECTOR_OFFSET as i64,
     };
-    let _song_path = matches.value_of("songs");
-    song_path = match _song_path {
-        Some(path) => validate_dir(path),
-        None => None,
-    };
-    let _data_path = matches.value_of("data");
-    data_path = match _data_path {
-        Some(path) => validate_dir(path),
-        None => None,
+
+    let output = match matches.value_of("output") {
+        Some(path) => PathBuf::from(path),
+        None => {
+            let name = kfile.cdg_path.file_name().unwrap_or_default();
+            song_file(Path::new(name), "mp4")
+        }
     };
+
+    render(&kfile, &output, fps, offset)
+}
+
+fn get_config(matches: &ArgMatches) -> Result<Config, failure::Error> {
+    //Return each path if valid, panic if not
+    let config_path = matches.value_of("config").and_then(validate_file);
+    let song_path = matches.value_of("songs").and_then(validate_dir);
+    let data_path = matches.value_of("data").and_then(validate_dir);
     let no_collection_update = if matches.is_present("no-collection-update") {
         Some(true)
     } else {
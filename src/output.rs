@@ -0,0 +1,543 @@
+use glium::{glutin, Surface};
+use glutin::{Event, KeyboardInput, VirtualKeyCode, WindowEvent};
+use image::RgbaImage;
+use karaoke::{
+    embed::Assets,
+    overlay::{self, join_overlay},
+    site::get_lan_url,
+    theme::{Backdrop, CdgFilter, Color, PlayerTheme, Slideshow},
+};
+use log::error;
+use rodio::{Sink, Source};
+use std::{
+    fs::File,
+    io::{BufReader, Cursor},
+    path::Path,
+    sync::{
+        atomic::{AtomicIsize, AtomicUsize, Ordering::SeqCst},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+//How often the idle screen checks whether the site's address has changed,
+//ie. the network came up after the player started
+const LAN_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+//Longest the window goes without its events being handled while idle, any
+//longer and the OS may report it as not responding
+const EVENT_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum VideoEvent {
+    //Output needs the idle screen drawn again, ie. window regained focus
+    Redraw,
+    //Viewer asked to quit the current song
+    Skip,
+}
+
+//Surface the player draws the idle screen and cdg frames to. Idle screen can
+//carry a notice, ie. why the last song was skipped. Cdg frames are drawn over
+//the theme's backdrop, cdg_changed is false when the image is the same as the
+//last frame's so outputs can skip copying it again
+pub trait VideoOutput {
+    fn draw_idle(&mut self, notice: Option<&str>) -> Result<(), failure::Error>;
+    fn draw_frame(
+        &mut self,
+        backdrop: Backdrop,
+        cdg: &RgbaImage,
+        cdg_changed: bool,
+    ) -> Result<(), failure::Error>;
+    fn poll_events(&mut self) -> Vec<VideoEvent>;
+    //When the idle player should next wake to poll events or redraw, if ever.
+    //Windows need their events handled regularly
+    fn wake_at(&self) -> Option<Instant> {
+        None
+    }
+}
+
+//Device the player sends song audio to. Position is used to keep cdg frames
+//in sync with the music, so it doesn't move on while paused. Volume is a
+//percentage and key a semitone offset, both carry over to the next song
+pub trait AudioOutput {
+    fn play(&mut self, path: &Path) -> Result<(), failure::Error>;
+    fn position(&self) -> Duration;
+    fn stop(&mut self);
+    fn pause(&mut self, paused: bool);
+    fn set_volume(&mut self, volume: u8);
+    fn set_key(&mut self, key: i8);
+}
+
+//Fullscreen glium window, default video output
+pub struct GliumVideo {
+    pub events_loop: glutin::EventsLoop,
+    pub display: glium::Display,
+    pub dimensions: glutin::dpi::LogicalSize,
+    pub background: glium::texture::Texture2d,
+    //QR code & URL guests join from, with the URL it shows. None without a network
+    pub join_overlay: Option<glium::texture::Texture2d>,
+    lan_url: Option<String>,
+    lan_checked: Instant,
+    slideshow: Option<Slideshow>,
+    //Backdrop image last uploaded, with its frame number
+    backdrop: Option<(u64, glium::texture::Texture2d)>,
+    //Cdg frames of the current song, updated in place as lyrics change
+    cdg: Option<glium::texture::Texture2d>,
+    theme: PlayerTheme,
+    letterbox: Color,
+}
+
+impl GliumVideo {
+    pub fn new(theme: &PlayerTheme) -> Self {
+        //Setup event loop & display
+        let events_loop = glutin::EventsLoop::new();
+        let wb = glutin::WindowBuilder::new()
+            .with_fullscreen(Some(events_loop.get_primary_monitor()))
+            .with_title("Karaoke-rs");
+        //Vsync makes finishing a frame wait for the screen, pacing the player
+        let cb = glutin::ContextBuilder::new().with_vsync(true);
+        let display = glium::Display::new(wb, cb, &events_loop).unwrap();
+
+        //Get dimensions of fullscreen window
+        let gl_window = display.gl_window();
+        let window = gl_window.window();
+        let dimensions = window.get_inner_size().unwrap();
+        drop(gl_window);
+
+        //Idle image from the theme's slideshow or image, falling back to the built in one
+        let interval = Duration::from_secs(theme.slideshow_interval);
+        let slideshow = theme.idle_slideshow.as_ref().and_then(|dir| {
+            Slideshow::open(dir, interval)
+                .map_err(|e| error!("Failed to open idle slideshow: {}", e))
+                .ok()
+        });
+        let idle_image = match &slideshow {
+            Some(slideshow) => Some(slideshow.current().to_path_buf()),
+            None => theme.idle_image.clone(),
+        };
+        let image = idle_image
+            .and_then(|path| {
+                load_image(&path)
+                    .map_err(|e| error!("Failed to load idle image {:?}: {}", path, e))
+                    .ok()
+            })
+            .unwrap_or_else(|| {
+                image::load(
+                    Cursor::new(&Assets::get("background.png").unwrap()[..]),
+                    image::PNG,
+                )
+                .unwrap()
+                .to_rgba()
+            });
+        let background = upload(&display, &image).unwrap();
+
+        //Render QR code & URL of web UI into Texture2d, shown while idle
+        let lan_url = get_lan_url();
+        let join_overlay = lan_url
+            .as_ref()
+            .map(|url| upload(&display, &join_overlay(url).unwrap()).unwrap());
+
+        GliumVideo {
+            events_loop,
+            display,
+            dimensions,
+            background,
+            join_overlay,
+            lan_url,
+            lan_checked: Instant::now(),
+            slideshow,
+            backdrop: None,
+            cdg: None,
+            theme: theme.clone(),
+            letterbox: theme.letterbox(),
+        }
+    }
+
+    //Renders the join overlay again if the site's address changed, returns
+    //whether it did
+    fn check_lan_url(&mut self) -> bool {
+        self.lan_checked = Instant::now();
+        let lan_url = get_lan_url();
+        if lan_url == self.lan_url {
+            return false;
+        }
+        let overlay = match &lan_url {
+            Some(url) => match join_overlay(url).and_then(|image| upload(&self.display, &image)) {
+                Ok(overlay) => Some(overlay),
+                Err(e) => {
+                    error!("Failed to draw join overlay: {}", e);
+                    return false;
+                }
+            },
+            None => None,
+        };
+        self.join_overlay = overlay;
+        self.lan_url = lan_url;
+        true
+    }
+
+    //Moves the slideshow on if its time, keeping the current image if the next fails to load
+    fn advance_slideshow(&mut self) {
+        let path = match &mut self.slideshow {
+            Some(slideshow) if slideshow.due() => {
+                slideshow.advance();
+                slideshow.current().to_path_buf()
+            }
+            _ => return,
+        };
+        match load_image(&path).and_then(|image| upload(&self.display, &image)) {
+            Ok(texture) => self.background = texture,
+            Err(e) => error!("Failed to load idle image {:?}: {}", path, e),
+        }
+    }
+
+    fn screen(&self) -> (u32, u32) {
+        (self.dimensions.width as u32, self.dimensions.height as u32)
+    }
+}
+
+fn load_image(path: &Path) -> Result<RgbaImage, failure::Error> {
+    Ok(image::open(path)?.to_rgba())
+}
+
+fn upload(
+    display: &glium::Display,
+    image: &RgbaImage,
+) -> Result<glium::texture::Texture2d, failure::Error> {
+    let image_dimensions = image.dimensions();
+    let image = glium::texture::RawImage2d::from_raw_rgba_reversed(image, image_dimensions);
+    Ok(glium::texture::Texture2d::new(display, image)?)
+}
+
+//Largest rect with the texture's aspect ratio that fits the screen, centered
+fn fit_rect(width: u32, height: u32, screen: (u32, u32)) -> glium::BlitTarget {
+    let scale = (screen.0 as f32 / width as f32).min(screen.1 as f32 / height as f32);
+    let fit_width = (width as f32 * scale) as u32;
+    let fit_height = (height as f32 * scale) as u32;
+    glium::BlitTarget {
+        left: screen.0.saturating_sub(fit_width) / 2,
+        bottom: screen.1.saturating_sub(fit_height) / 2,
+        width: fit_width as i32,
+        height: fit_height as i32,
+    }
+}
+
+impl VideoOutput for GliumVideo {
+    fn draw_idle(&mut self, notice: Option<&str>) -> Result<(), failure::Error> {
+        //Song is over, its backdrop and cdg frames won't be drawn again
+        self.backdrop = None;
+        self.cdg = None;
+        self.advance_slideshow();
+
+        let mut frame = self.display.draw();
+        let letterbox = self.letterbox;
+        frame.clear_color(letterbox.0, letterbox.1, letterbox.2, letterbox.3);
+
+        let background_rect = fit_rect(
+            self.background.width(),
+            self.background.height(),
+            self.screen(),
+        );
+        self.background.as_surface().blit_whole_color_to(
+            &frame,
+            &background_rect,
+            glium::uniforms::MagnifySamplerFilter::Linear,
+        );
+
+        //Bottom right corner, with a small margin
+        let margin = 32;
+        if let Some(join_overlay) = &self.join_overlay {
+            let overlay_rect = glium::BlitTarget {
+                left: (self.dimensions.width as u32).saturating_sub(join_overlay.width() + margin),
+                bottom: margin,
+                width: join_overlay.width() as i32,
+                height: join_overlay.height() as i32,
+            };
+            join_overlay.as_surface().blit_whole_color_to(
+                &frame,
+                &overlay_rect,
+                glium::uniforms::MagnifySamplerFilter::Linear,
+            );
+        }
+
+        //Top left corner, same margin. Only drawn on demand, so uploaded each time
+        if let Some(text) = notice {
+            let texture = upload(&self.display, &overlay::notice(text))?;
+            let notice_rect = glium::BlitTarget {
+                left: margin,
+                bottom: (self.dimensions.height as u32).saturating_sub(texture.height() + margin),
+                width: texture.width() as i32,
+                height: texture.height() as i32,
+            };
+            texture.as_surface().blit_whole_color_to(
+                &frame,
+                &notice_rect,
+                glium::uniforms::MagnifySamplerFilter::Linear,
+            );
+        }
+
+        frame.finish()?;
+        Ok(())
+    }
+
+    fn draw_frame(
+        &mut self,
+        backdrop: Backdrop,
+        cdg: &RgbaImage,
+        cdg_changed: bool,
+    ) -> Result<(), failure::Error> {
+        let mut frame = self.display.draw();
+        match backdrop {
+            Backdrop::Color(color) => frame.clear_color(color.0, color.1, color.2, color.3),
+            Backdrop::Image {
+                image,
+                frame: number,
+                stretch,
+            } => {
+                let letterbox = self.letterbox;
+                frame.clear_color(letterbox.0, letterbox.1, letterbox.2, letterbox.3);
+
+                //Only uploaded when it's changed since the last frame
+                let uploaded = match &self.backdrop {
+                    Some((uploaded, _)) => *uploaded == number,
+                    None => false,
+                };
+                if !uploaded {
+                    self.backdrop = Some((number, upload(&self.display, image)?));
+                }
+                let texture = &self.backdrop.as_ref().unwrap().1;
+                let rect = if stretch {
+                    glium::BlitTarget {
+                        left: 0,
+                        bottom: 0,
+                        width: self.dimensions.width as i32,
+                        height: self.dimensions.height as i32,
+                    }
+                } else {
+                    fit_rect(texture.width(), texture.height(), self.screen())
+                };
+                texture.as_surface().blit_whole_color_to(
+                    &frame,
+                    &rect,
+                    glium::uniforms::MagnifySamplerFilter::Linear,
+                );
+            }
+        }
+
+        //Write cdg frame over the song's texture when it's changed, blit the
+        //shown part of the texture to frame surface, sized & filtered by the theme
+        let (cdg_width, cdg_height) = cdg.dimensions();
+        let reusable = match &self.cdg {
+            Some(texture) => texture.dimensions() == (cdg_width, cdg_height),
+            None => false,
+        };
+        if !reusable {
+            self.cdg = Some(upload(&self.display, cdg)?);
+        } else if cdg_changed {
+            let image =
+                glium::texture::RawImage2d::from_raw_rgba_reversed(cdg, (cdg_width, cdg_height));
+            let rect = glium::Rect {
+                left: 0,
+                bottom: 0,
+                width: cdg_width,
+                height: cdg_height,
+            };
+            self.cdg.as_ref().unwrap().write(rect, image);
+        }
+        let cdg_image = self.cdg.as_ref().unwrap();
+        let (left, bottom, width, height) = self.theme.cdg_source(cdg.dimensions());
+        let source_rect = glium::Rect {
+            left,
+            bottom,
+            width,
+            height,
+        };
+        let (left, bottom, width, height) = self.theme.cdg_target(cdg.dimensions(), self.screen());
+        let cdg_rect = glium::BlitTarget {
+            left,
+            bottom,
+            width: width as i32,
+            height: height as i32,
+        };
+        let filter = match self.theme.cdg_filter {
+            CdgFilter::Nearest => glium::uniforms::MagnifySamplerFilter::Nearest,
+            CdgFilter::Linear => glium::uniforms::MagnifySamplerFilter::Linear,
+        };
+        cdg_image
+            .as_surface()
+            .blit_color(&source_rect, &frame, &cdg_rect, filter);
+
+        //Render
+        frame.finish()?;
+        Ok(())
+    }
+
+    fn wake_at(&self) -> Option<Instant> {
+        let events = Instant::now() + EVENT_INTERVAL;
+        let slide = self.slideshow.as_ref().and_then(Slideshow::next_due);
+        Some(slide.map_or(events, |slide| slide.min(events)))
+    }
+
+    //Asks for a redraw when the idle slideshow should move on, or the site's
+    //address changed
+    fn poll_events(&mut self) -> Vec<VideoEvent> {
+        let mut events = Vec::new();
+        if self.slideshow.as_ref().is_some_and(Slideshow::due) {
+            events.push(VideoEvent::Redraw);
+        }
+        if self.lan_checked.elapsed() >= LAN_CHECK_INTERVAL && self.check_lan_url() {
+            events.push(VideoEvent::Redraw);
+        }
+        self.events_loop.poll_events(|event| {
+            if let Event::WindowEvent { event, .. } = event {
+                match event {
+                    WindowEvent::Focused(_) => events.push(VideoEvent::Redraw),
+                    WindowEvent::CloseRequested => events.push(VideoEvent::Skip),
+                    WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                virtual_keycode: Some(VirtualKeyCode::Escape),
+                                ..
+                            },
+                        ..
+                    } => events.push(VideoEvent::Skip),
+                    _ => (),
+                }
+            }
+        });
+        events
+    }
+}
+
+//Default system output device through rodio
+pub struct RodioAudio {
+    sink: Option<Sink>,
+    counter: Arc<AtomicUsize>,
+    volume: u8,
+    key: Arc<AtomicIsize>,
+}
+
+impl Default for RodioAudio {
+    fn default() -> Self {
+        RodioAudio {
+            sink: None,
+            counter: Arc::default(),
+            volume: 100,
+            key: Arc::default(),
+        }
+    }
+}
+
+impl RodioAudio {
+    pub fn new() -> Self {
+        RodioAudio::default()
+    }
+}
+
+//Changes key like a turntable does, by playing the song faster or slower. A
+//semitone is a 2^(1/12) change in speed. The sample rate is read at the start
+//of each mp3 frame, so a new key takes effect during the song
+struct KeyShift<I> {
+    input: I,
+    key: Arc<AtomicIsize>,
+}
+
+impl<I: Source> Iterator for KeyShift<I>
+where
+    I::Item: rodio::Sample,
+{
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<I::Item> {
+        self.input.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.input.size_hint()
+    }
+}
+
+impl<I: Source> Source for KeyShift<I>
+where
+    I::Item: rodio::Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        let speed = 2f32.powf(self.key.load(SeqCst) as f32 / 12.0);
+        (self.input.sample_rate() as f32 * speed).round() as u32
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+impl AudioOutput for RodioAudio {
+    //Create new output device, load mp3 into sound buffer, decode with rodio, setup periodic access
+    //to callback everytime 1ms has passed to track song position for synchronization
+    fn play(&mut self, path: &Path) -> Result<(), failure::Error> {
+        self.stop();
+
+        let device = rodio::default_output_device()
+            .ok_or_else(|| failure::err_msg("No audio output device available"))?;
+        let sink = Sink::new(&device);
+        let file = File::open(path)?;
+        let counter = Arc::from(AtomicUsize::new(0));
+        let periodic_counter = counter.clone();
+        let access_time = Duration::from_millis(1);
+        //Counted before the key change, so position stays in song time
+        let source =
+            rodio::Decoder::new(BufReader::new(file))?.periodic_access(access_time, move |_| {
+                let _ = periodic_counter.fetch_add(1, SeqCst);
+            });
+        let source = KeyShift {
+            input: source,
+            key: self.key.clone(),
+        };
+
+        //Play it!
+        sink.set_volume(f32::from(self.volume) / 100.0);
+        sink.append(source);
+
+        self.sink = Some(sink);
+        self.counter = counter;
+        Ok(())
+    }
+
+    fn position(&self) -> Duration {
+        Duration::from_millis(self.counter.load(SeqCst) as u64)
+    }
+
+    //Dropping the sink stops playback
+    fn stop(&mut self) {
+        self.sink.take();
+    }
+
+    fn pause(&mut self, paused: bool) {
+        if let Some(sink) = &self.sink {
+            if paused {
+                sink.pause();
+            } else {
+                sink.play();
+            }
+        }
+    }
+
+    fn set_volume(&mut self, volume: u8) {
+        self.volume = volume;
+        if let Some(sink) = &self.sink {
+            sink.set_volume(f32::from(volume) / 100.0);
+        }
+    }
+
+    fn set_key(&mut self, key: i8) {
+        self.key.store(isize::from(key), SeqCst);
+    }
+}
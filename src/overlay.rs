@@ -0,0 +1,151 @@
+use image::{Rgba, RgbaImage};
+use karaoke::embed::Assets;
+use lazy_static::lazy_static;
+use qrcode::{types::Color, QrCode};
+use rusttype::{point, Font, Scale};
+
+lazy_static! {
+    pub static ref FONT: Font<'static> = {
+        let data = Assets::get("DejaVuSans.ttf").unwrap().into_owned();
+        Font::from_bytes(data).unwrap()
+    };
+}
+
+//Size in pixels of each QR module, and number of light modules surrounding the code
+const QR_MODULE_SIZE: u32 = 8;
+const QR_QUIET_ZONE: u32 = 4;
+
+const TEXT_SIZE: f32 = 28.0;
+const PADDING: u32 = 16;
+
+const WHITE: Rgba<u8> = Rgba {
+    data: [255, 255, 255, 255],
+};
+const BLACK: Rgba<u8> = Rgba {
+    data: [0, 0, 0, 255],
+};
+const NOTICE_BACKGROUND: Rgba<u8> = Rgba {
+    data: [32, 32, 32, 255],
+};
+
+//Builds a white panel with a QR code linking to the web UI, with the URL
+//printed underneath so it can also be typed in by hand
+pub fn join_overlay(url: &str) -> Result<RgbaImage, failure::Error> {
+    let code = QrCode::new(url.as_bytes())?;
+    let modules = code.width() as u32;
+    let qr_size = (modules + QR_QUIET_ZONE * 2) * QR_MODULE_SIZE;
+
+    let scale = Scale::uniform(TEXT_SIZE);
+    let url_width = text_width(url, scale).ceil() as u32;
+    let width = qr_size.max(url_width + PADDING * 2);
+    let height = qr_size + TEXT_SIZE as u32 + PADDING;
+
+    let mut image = RgbaImage::from_pixel(width, height, WHITE);
+
+    //Draw each dark module as a filled square, centered horizontally
+    let qr_left = (width - qr_size) / 2 + QR_QUIET_ZONE * QR_MODULE_SIZE;
+    let qr_top = QR_QUIET_ZONE * QR_MODULE_SIZE;
+    for (i, color) in code.to_colors().into_iter().enumerate() {
+        if color != Color::Dark {
+            continue;
+        }
+        let x = qr_left + (i as u32 % modules) * QR_MODULE_SIZE;
+        let y = qr_top + (i as u32 / modules) * QR_MODULE_SIZE;
+        for dy in 0..QR_MODULE_SIZE {
+            for dx in 0..QR_MODULE_SIZE {
+                image.put_pixel(x + dx, y + dy, BLACK);
+            }
+        }
+    }
+
+    let text_left = (width - url_width) / 2;
+    draw_text(
+        &mut image,
+        url,
+        scale,
+        text_left as i32,
+        qr_size as i32,
+        BLACK,
+    );
+
+    Ok(image)
+}
+
+//Dark panel with a single line of white text, for messages shown over the
+//idle screen
+pub fn notice(text: &str) -> RgbaImage {
+    let scale = Scale::uniform(TEXT_SIZE);
+    let width = text_width(text, scale).ceil() as u32 + PADDING * 2;
+    let height = TEXT_SIZE as u32 + PADDING * 2;
+
+    let mut image = RgbaImage::from_pixel(width, height, NOTICE_BACKGROUND);
+    draw_text(
+        &mut image,
+        text,
+        scale,
+        PADDING as i32,
+        PADDING as i32,
+        WHITE,
+    );
+    image
+}
+
+//Width in pixels of a single line of text rendered at scale
+pub fn text_width(text: &str, scale: Scale) -> f32 {
+    FONT.layout(text, scale, point(0.0, 0.0))
+        .last()
+        .map(|g| g.position().x + g.unpositioned().h_metrics().advance_width)
+        .unwrap_or(0.0)
+}
+
+//Rasterizes a single line of text onto image, with top left corner at (x, y).
+//Glyph coverage is used to blend color over the existing pixels
+pub fn draw_text(image: &mut RgbaImage, text: &str, scale: Scale, x: i32, y: i32, color: Rgba<u8>) {
+    let ascent = FONT.v_metrics(scale).ascent;
+    let (width, height) = image.dimensions();
+
+    for glyph in FONT.layout(text, scale, point(x as f32, y as f32 + ascent)) {
+        if let Some(bb) = glyph.pixel_bounding_box() {
+            glyph.draw(|gx, gy, coverage| {
+                let px = gx as i32 + bb.min.x;
+                let py = gy as i32 + bb.min.y;
+                if px < 0 || py < 0 || px >= width as i32 || py >= height as i32 {
+                    return;
+                }
+                let pixel = image.get_pixel_mut(px as u32, py as u32);
+                for (p, c) in pixel.data.iter_mut().zip(color.data.iter()).take(3) {
+                    let blended = f32::from(*p) * (1.0 - coverage) + f32::from(*c) * coverage;
+                    *p = blended.round() as u8;
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join_overlay() {
+        let url = "http://192.168.1.10:8080";
+        let image = join_overlay(url).unwrap();
+        let (width, height) = image.dimensions();
+        assert!(width >= text_width(url, Scale::uniform(TEXT_SIZE)) as u32);
+        assert!(height > width / 2);
+
+        //Quiet zone must stay light, first module of finder pattern must be dark
+        let edge = QR_QUIET_ZONE * QR_MODULE_SIZE;
+        let qr_size = height - TEXT_SIZE as u32 - PADDING;
+        let qr_left = (width - qr_size) / 2;
+        assert_eq!(*image.get_pixel(qr_left, 0), WHITE);
+        assert_eq!(*image.get_pixel(qr_left + edge, edge), BLACK);
+    }
+
+    #[test]
+    fn test_notice() {
+        let image = notice("Skipped song");
+        assert_eq!(*image.get_pixel(0, 0), NOTICE_BACKGROUND);
+        assert!(image.pixels().any(|p| p.data[0] > 128));
+    }
+}
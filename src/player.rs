@@ -1,241 +1,344 @@
-use crossbeam_channel::{select, Receiver, Sender};
-use glium::{glutin, Surface};
-use glutin::{Event, KeyboardInput, VirtualKeyCode, WindowEvent};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
 use image::GenericImage;
 use karaoke::{
-    channel::{LiveCommand, PlayerCommand, LIVE_CHANNEL, PLAYER_CHANNEL},
-    collection::Kfile,
-    embed::Assets,
-    queue::PLAY_QUEUE,
+    channel::PlayerCommand,
+    context::Context,
+    events::{Broadcaster, Event},
+    history::{History, HistoryEntry},
+    output::{AudioOutput, GliumVideo, RodioAudio, VideoEvent, VideoOutput},
+    queue::QueueEntry,
+    stats::Stats,
+    status::PlayerStatus,
+    theme::{BackdropSource, PlayerTheme},
 };
-use rodio::{Sink, Source};
+use log::error;
 use std::{
     cell::RefCell,
-    f32::consts,
     fs::File,
-    io::{BufReader, Cursor},
-    rc::Rc,
-    sync::{
-        atomic::{AtomicUsize, Ordering::SeqCst},
-        Arc, Mutex,
-    },
+    io::BufReader,
+    sync::{Arc, Mutex},
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-pub fn run() {
-    thread::spawn(move || {
-        let player = Player::new();
-        player.run();
+//How long a notice stays on the idle screen
+const NOTICE_DURATION: Duration = Duration::from_secs(10);
+
+//Shortest time one pass of the song loop takes, vsync usually waits longer
+const MIN_LOOP_TIME: Duration = Duration::from_millis(10);
+
+//Lyrics are drawn this many cdg sectors behind the audio, this syncs lyrics
+//to music almost perfectly
+pub const SECTOR_OFFSET: isize = 20;
+
+//Furthest the key can be moved in semitones. Key changes speed up or slow down
+//the song, so beyond this it sounds too far off
+pub const MAX_KEY: i8 = 6;
+
+//Plays to a fullscreen window & the default audio device
+pub fn run(context: &Context) {
+    let theme = context.config.player.clone();
+    spawn(context, move || {
+        let video = GliumVideo::new(&theme);
+        let audio = RodioAudio::new();
+        (
+            Box::new(video) as Box<dyn VideoOutput>,
+            Box::new(audio) as Box<dyn AudioOutput>,
+        )
     });
 }
 
-#[derive(Eq, PartialEq, Debug)]
-pub enum PlayerStatus {
-    Playing,
-    Stopped,
+//Runs a player with custom outputs. Outputs are created on the player thread,
+//as a GL context can't be moved between threads
+pub fn spawn<F>(context: &Context, outputs: F)
+where
+    F: FnOnce() -> (Box<dyn VideoOutput>, Box<dyn AudioOutput>) + Send + 'static,
+{
+    let context = context.clone();
+    thread::spawn(move || {
+        let (video, audio) = outputs();
+        let player = Player::new(&context, video, audio);
+        player.run();
+    });
 }
 
 pub struct Player {
-    pub status: Rc<RefCell<PlayerStatus>>,
+    pub status: Arc<Mutex<PlayerStatus>>,
     pub player_sender: Sender<PlayerCommand>,
     pub player_receiver: Receiver<PlayerCommand>,
-    pub live_sender: Sender<LiveCommand>,
-    pub live_receiver: Receiver<LiveCommand>,
-    pub queue: Arc<Mutex<Vec<Kfile>>>,
-    pub events_loop: Rc<RefCell<glutin::EventsLoop>>,
-    pub display: glium::Display,
-    pub dimensions: glutin::dpi::LogicalSize,
-    pub background: glium::texture::Texture2d,
+    pub queue: Arc<Mutex<Vec<QueueEntry>>>,
+    pub events: Arc<Mutex<Broadcaster>>,
+    pub history: Arc<Mutex<History>>,
+    pub stats: Arc<Mutex<Stats>>,
+    pub video: RefCell<Box<dyn VideoOutput>>,
+    pub audio: RefCell<Box<dyn AudioOutput>>,
+    pub theme: PlayerTheme,
+    notice: RefCell<Option<(String, Instant)>>,
 }
 
 impl Player {
-    pub fn new() -> Self {
-        let status = Rc::from(RefCell::from(PlayerStatus::Stopped));
-        let queue = PLAY_QUEUE.clone();
-
-        //Setup event loop & display
-        let events_loop = glutin::EventsLoop::new();
-        let wb = glutin::WindowBuilder::new()
-            .with_fullscreen(Some(events_loop.get_primary_monitor()))
-            .with_title("Karaoke-rs");
-        let cb = glutin::ContextBuilder::new();
-        let display = glium::Display::new(wb, cb, &events_loop).unwrap();
-
-        //Get dimensions of fullscreen window
-        let gl_window = display.gl_window();
-        let window = gl_window.window();
-        let dimensions = window.get_inner_size().unwrap();
-        drop(gl_window);
-
-        //Load background image into Texture2d
-        let image = image::load(
-            Cursor::new(&Assets::get("background.png").unwrap()[..]),
-            image::PNG,
-        )
-        .unwrap()
-        .to_rgba();
-        let image_dimensions = image.dimensions();
-        let image =
-            glium::texture::RawImage2d::from_raw_rgba_reversed(&image.into_raw(), image_dimensions);
-        let background = glium::texture::Texture2d::new(&display, image).unwrap();
-
+    pub fn new(
+        context: &Context,
+        video: Box<dyn VideoOutput>,
+        audio: Box<dyn AudioOutput>,
+    ) -> Self {
         Player {
-            status,
-            player_sender: PLAYER_CHANNEL.0.clone(),
-            player_receiver: PLAYER_CHANNEL.1.clone(),
-            live_sender: LIVE_CHANNEL.0.clone(),
-            live_receiver: LIVE_CHANNEL.1.clone(),
-            queue,
-            events_loop: Rc::from(RefCell::from(events_loop)),
-            display,
-            dimensions,
-            background,
+            status: context.status.clone(),
+            player_sender: context.player_channel.0.clone(),
+            player_receiver: context.player_channel.1.clone(),
+            queue: context.queue.clone(),
+            events: context.events.clone(),
+            history: context.history.clone(),
+            stats: context.stats.clone(),
+            video: RefCell::from(video),
+            audio: RefCell::from(audio),
+            theme: context.config.player.clone(),
+            notice: RefCell::from(None),
         }
     }
 
+    //Blocks until a command arrives, or the video output next wants to wake,
+    //ie. to handle window events or redraw the idle screen. Window events are
+    //handled whenever the player wakes
     pub fn run(&self) {
-        self.clear_background().unwrap();
+        self.redraw_idle();
 
         loop {
-            select! {
-                recv(self.player_receiver) -> cmd => self.process_cmd(cmd.unwrap()),
-                default() => self.check_queue(),
+            let cmd = match self.idle_timeout() {
+                Some(timeout) => match self.player_receiver.recv_timeout(timeout) {
+                    Ok(cmd) => Some(cmd),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                },
+                None => match self.player_receiver.recv() {
+                    Ok(cmd) => Some(cmd),
+                    Err(_) => return,
+                },
             };
-            std::thread::sleep(Duration::from_millis(50));
+            if let Some(cmd) = cmd {
+                self.process_cmd(cmd);
+            }
 
-            self.events_loop.borrow_mut().poll_events(|event| {
-                if let Event::WindowEvent { event, .. } = event {
-                    if let WindowEvent::Focused(_) = event {
-                        self.clear_background().unwrap();
-                    }
-                };
-            });
+            //Take down expired notice
+            let expired = match *self.notice.borrow() {
+                Some((_, until)) => Instant::now() >= until,
+                None => false,
+            };
+            if expired {
+                self.notice.borrow_mut().take();
+                self.redraw_idle();
+            }
+
+            let events = self.video.borrow_mut().poll_events();
+            if events.contains(&VideoEvent::Redraw) {
+                self.redraw_idle();
+            }
         }
     }
 
-    pub fn clear_background(&self) -> Result<(), failure::Error> {
-        let mut frame = self.display.draw();
-        frame.clear_color(0.0, 0.0, 0.0, 1.0);
-
-        let background_rect = glium::BlitTarget {
-            left: 0,
-            bottom: 0,
-            width: self.dimensions.width as i32,
-            height: self.dimensions.height as i32,
+    //Time until the notice expires or the video output wants waking, ie. for
+    //the next slide or window events. None when neither will ever need it
+    fn idle_timeout(&self) -> Option<Duration> {
+        let notice = self.notice.borrow().as_ref().map(|(_, until)| *until);
+        let video = self.video.borrow().wake_at();
+        let wake = match (notice, video) {
+            (Some(notice), Some(video)) => Some(notice.min(video)),
+            (notice, video) => notice.or(video),
         };
-        self.background.as_surface().blit_whole_color_to(
-            &frame,
-            &background_rect,
-            glium::uniforms::MagnifySamplerFilter::Linear,
-        );
-
-        frame.finish()?;
-        Ok(())
+        let now = Instant::now();
+        wake.map(|wake| {
+            if wake > now {
+                wake - now
+            } else {
+                Duration::from_secs(0)
+            }
+        })
+    }
+
+    pub fn clear_background(&self) -> Result<(), failure::Error> {
+        let notice = self.notice.borrow();
+        let text = notice.as_ref().map(|(text, _)| text.as_str());
+        self.video.borrow_mut().draw_idle(text)
     }
 
-    pub fn stop(&self) {
-        self.live_sender.send(LiveCommand::Stop).unwrap();
+    //Drawing errors are logged rather than ending the player, a later redraw
+    //may well succeed
+    fn redraw_idle(&self) {
+        if let Err(e) = self.clear_background() {
+            error!("Failed to draw idle screen: {}", e);
+        }
     }
 
-    pub fn check_queue(&self) {
+    //Plays the song at the front of the queue, returns false if queue is empty.
+    //Commands sent while nothing was playing are handled first, so a Stop meant
+    //for a song that already ended doesn't cut this one short
+    pub fn check_queue(&self) -> bool {
+        if let Some(entry) = self.take_commands(None) {
+            self.play(entry);
+            return true;
+        }
         let mut queue = self.queue.lock().unwrap();
         if queue.is_empty() {
             drop(queue);
-            return;
+            return false;
         }
-        let kfile = queue.remove(0);
+        let entry = queue.remove(0);
         drop(queue);
-        self.play(kfile);
+        self.play(entry);
+        true
+    }
+
+    //Plays entry, followed by any song requested to play now while it's playing
+    pub fn play(&self, entry: QueueEntry) {
+        let mut next = Some(entry);
+        while let Some(entry) = next.take() {
+            let song = format!("{} - {}", entry.kfile.artist, entry.kfile.song);
+            next = match self.play_song(entry) {
+                Ok(next) => next,
+                Err(e) => {
+                    self.playback_failed(song, &e);
+                    None
+                }
+            };
+        }
     }
 
-    pub fn play(&self, kfile: Kfile) {
-        std::thread::sleep(Duration::from_millis(100));
-        if *self.status.borrow() == PlayerStatus::Playing {
-            self.stop();
+    //Leaves the player idle with a notice of what went wrong, ready for the
+    //next song in queue
+    fn playback_failed(&self, song: String, e: &failure::Error) {
+        error!("Failed to play {}: {}", song, e);
+        self.audio.borrow_mut().stop();
+        self.record_history(true);
+
+        let was_playing = {
+            let mut status = self.status.lock().unwrap();
+            let was_playing = status.is_playing();
+            status.stop();
+            was_playing
+        };
+        if was_playing {
+            self.status_changed();
         }
-        std::thread::sleep(Duration::from_millis(100));
-        self.empty_stale_live();
-        self.play_song(kfile).unwrap();
+
+        let notice = format!("Skipped {}: {}", song, e);
+        *self.notice.borrow_mut() = Some((notice, Instant::now() + NOTICE_DURATION));
+        self.redraw_idle();
+        self.broadcast(Event::PlaybackError {
+            song,
+            message: e.to_string(),
+        });
     }
 
+    //Once handled, keep playing until the queue runs out
     fn process_cmd(&self, cmd: PlayerCommand) {
-        match cmd {
-            PlayerCommand::Play { kfile } => self.play(kfile),
+        if let Some(entry) = self.take_commands(Some(cmd)) {
+            self.play(entry);
         }
+        while self.check_queue() {}
     }
 
-    fn empty_stale_live(&self) {
-        select! {
-            recv(self.live_receiver) -> _ => { },
-            default() => { },
-        };
+    //Takes first along with every command already waiting, in order, returning
+    //the song to play if any. A Play replaced by a later Play, or stopped
+    //before it started, is never played. Stops with nothing to stop are meant
+    //for a song that already ended
+    fn take_commands(&self, first: Option<PlayerCommand>) -> Option<QueueEntry> {
+        let mut play = None;
+        for cmd in first.into_iter().chain(self.player_receiver.try_iter()) {
+            match cmd {
+                PlayerCommand::Play { entry } => play = Some(entry),
+                PlayerCommand::Stop => play = None,
+                PlayerCommand::CheckQueue => {}
+                cmd => self.adjust(self.audio.borrow_mut().as_mut(), cmd),
+            }
+        }
+        play
+    }
+
+    //Applies a pause, volume or key change to the audio, letting clients know
+    fn adjust(&self, audio: &mut dyn AudioOutput, cmd: PlayerCommand) {
+        let mut status = self.status.lock().unwrap();
+        match cmd {
+            PlayerCommand::Pause { paused } => {
+                if !status.is_playing() || status.paused == paused {
+                    return;
+                }
+                audio.pause(paused);
+                status.paused = paused;
+            }
+            PlayerCommand::Volume { volume } => {
+                let volume = volume.min(100);
+                audio.set_volume(volume);
+                status.volume = volume;
+            }
+            PlayerCommand::Key { key } => {
+                let key = key.clamp(-MAX_KEY, MAX_KEY);
+                audio.set_key(key);
+                status.key = key;
+            }
+            _ => return,
+        }
+        drop(status);
+        self.status_changed();
     }
 
-    fn play_song(&self, kfile: Kfile) -> Result<(), failure::Error> {
-        *self.status.borrow_mut() = PlayerStatus::Playing;
-
-        //Create new output device, load mp3 into sound buffer, decode with rodio, setup periodic access
-        //to callback everytime 1ms has passed to track song position for synchronization
-        let device = rodio::default_output_device().unwrap();
-        let sink = Sink::new(&device);
-        let file = File::open(&kfile.mp3_path)?;
-        let counter = Arc::from(AtomicUsize::new(0));
-        let periodic_counter = counter.clone();
-        let access_time = Duration::from_millis(1);
-        let source =
-            rodio::Decoder::new(BufReader::new(file))?.periodic_access(access_time, move |_| {
-                let _ = periodic_counter.fetch_add(1, SeqCst);
-            });
+    //Returns a song requested to play now, which interrupted this one
+    fn play_song(&self, entry: QueueEntry) -> Result<Option<QueueEntry>, failure::Error> {
+        self.notice.borrow_mut().take();
+        let mut video = self.video.borrow_mut();
+        let mut audio = self.audio.borrow_mut();
 
         //Load cdg, create Subchannel Iterator to cycle through cdg sectors
-        let cdg = File::open(&kfile.cdg_path)?;
+        let cdg = File::open(&entry.kfile.cdg_path)?;
         let mut scsi = cdg::SubchannelStreamIter::new(BufReader::new(cdg));
 
-        //Size of cdg render texture, scaled at 1.5x
-        let cdg_x: f32 = 300.0;
-        let cdg_y: f32 = 216.0;
-        let cdg_scale = 1.5;
-
-        //Calculate center cdg image
-        let cdg_x_center = self.dimensions.width as f32 * 0.5 - (cdg_x * cdg_scale) * 0.5;
-        let cdg_y_center = self.dimensions.height as f32 * 0.5 - (cdg_y * cdg_scale) * 0.5;
-
-        //Counter and frequency for rainbow effect
-        let mut i: f32 = 0.0;
-        let size: f32 = 4096.0;
+        //Drawn behind the lyrics, a theme that can't be loaded falls back to the rainbow
+        let mut backdrop = BackdropSource::new(&self.theme).unwrap_or_else(|e| {
+            error!("Failed to load player background: {}", e);
+            BackdropSource::Rainbow { i: 0.0 }
+        });
 
         //Values to help keep rendered frames in sync with music
         let mut last_sector_no: isize = 0;
         let mut sectors_since: isize = 0;
+        let mut last_second = 0;
+        let mut finished = false;
+        let mut next = None;
 
         //Create CdgInterpreter, which will consume sector commands and produce
         //finished frames which can be copied into RgbaImage. Image data can then
-        //be fed into renderable in-GPU-memory image
+        //be drawn by the video output
         let mut cdg_interp = cdg_renderer::CdgInterpreter::new();
         let mut cdg_image = image::RgbaImage::new(300, 216);
 
         //Play it!
-        sink.append(source);
+        audio.play(&entry.kfile.mp3_path)?;
+        self.status.lock().unwrap().start(entry);
+        self.status_changed();
 
         //Loop will get current song position, calculate how many "cdg sectors"
         //have elasped in total (1 sector = 1/75th of a second), and subtract
         //last_sector_no to determine how many sectors worth of cdg commands need
         //to be iterated and processed by the CdgInterpreter. RGBA data can then
-        //be copied out of the interpreter and blitted to frame surface
+        //be copied out of the interpreter and drawn to the video output
         //
-        //Every time a new frame is rendered, the background texture color will
-        //update based on a sine wave function to smoothly cycle through the
-        //rainbow.
+        //Every time a new frame is rendered, the backdrop moves on, ie. the
+        //rainbow color cycles or the video loop shows its latest frame.
         //
         //Current song can be stopped with either ESC key or receiving a Stop
-        //command.
+        //command, or replaced by receiving a Play command.
         'player: loop {
-            let track_pos = counter.load(SeqCst);
+            let loop_start = Instant::now();
+            let track_pos = audio.position().as_millis();
+            self.status.lock().unwrap().position = track_pos as u64;
+
+            //Let clients know where playback is about once a second
+            let second = track_pos / 1000;
+            if second != last_second {
+                last_second = second;
+                self.broadcast(Event::Position(track_pos as u64));
+            }
 
-            //Offset rendering lyrics by 20 sectors, this syncs lyrics to music
-            //almost perfectly
-            let calc_sector = (track_pos as f32 / 13.333_333).floor() as isize - 20;
+            let calc_sector = (track_pos as f32 / 13.333_333).floor() as isize - SECTOR_OFFSET;
 
             if calc_sector >= 0 {
                 sectors_since = calc_sector - last_sector_no;
@@ -250,6 +353,7 @@ impl Player {
                             cdg_interp.handle_cmd(cmd);
                         }
                     } else {
+                        finished = true;
                         break 'player;
                     }
                 }
@@ -259,101 +363,70 @@ impl Player {
 
             //Don't start rendering until offset passes 0
             if sectors_since > 0 {
-                let mut frame = self.display.draw();
-
-                //Get background color from rainbow cycle, clear to window
-                let background_data = rainbow_cycle(&mut i, size);
-                frame.clear_color(
-                    background_data.0,
-                    background_data.1,
-                    background_data.2,
-                    background_data.3,
-                );
-
-                //Get updated cdg frame from interpreter, copy into RGBA image,
-                //update to texture, blit texture to frame surface with rectangle dimensions
-                cdg_image.copy_from(&cdg_interp, 0, 0);
-                let cdg_image = glium::texture::RawImage2d::from_raw_rgba_reversed(
-                    &cdg_image.clone().into_raw()[..],
-                    (cdg_x as u32, cdg_y as u32),
-                );
-                let cdg_image = glium::Texture2d::new(&self.display, cdg_image)?;
-                let cdg_rect = glium::BlitTarget {
-                    left: cdg_x_center as u32,
-                    bottom: cdg_y_center as u32,
-                    width: (cdg_x * cdg_scale) as i32,
-                    height: (cdg_y * cdg_scale) as i32,
-                };
-                cdg_image.as_surface().blit_whole_color_to(
-                    &frame,
-                    &cdg_rect,
-                    glium::uniforms::MagnifySamplerFilter::Linear,
-                );
-
-                //Render
-                frame.finish()?;
+                //Get next backdrop, copy cdg frame out of the interpreter into
+                //RGBA image only if the processed commands changed it
+                let cdg_changed = cdg_interp.dirty().is_some();
+                if cdg_changed {
+                    cdg_image.copy_from(&cdg_interp, 0, 0);
+                    cdg_interp.clear_dirty_region();
+                }
+                video.draw_frame(backdrop.frame(), &cdg_image, cdg_changed)?;
             }
 
             //Quit song if ESC key pressed
-            let mut _break = false;
-            self.events_loop.borrow_mut().poll_events(|event| {
-                if let Event::WindowEvent { event, .. } = event {
-                    match event {
-                        WindowEvent::CloseRequested => {
-                            _break = true;
-                        }
-                        WindowEvent::KeyboardInput {
-                            input:
-                                KeyboardInput {
-                                    virtual_keycode: Some(keycode),
-                                    ..
-                                },
-                            ..
-                        } => {
-                            if let VirtualKeyCode::Escape = keycode {
-                                _break = true;
-                            }
-                        }
-                        _ => (),
-                    }
-                }
-            });
-            if _break {
+            if video.poll_events().contains(&VideoEvent::Skip) {
                 break 'player;
-            };
+            }
 
-            //Check to see if Stop command is received for early exit
-            select! {
-                recv(self.live_receiver) -> cmd => {
-                    if cmd.unwrap() == LiveCommand::Stop {
-                        break 'player;
-                    }
-                },
-                default => {},
+            //Check to see if Stop or Play command is received for early exit
+            match self.player_receiver.try_recv() {
+                Ok(PlayerCommand::Stop) => break 'player,
+                Ok(PlayerCommand::Play { entry }) => {
+                    next = Some(entry);
+                    break 'player;
+                }
+                Ok(cmd) => self.adjust(audio.as_mut(), cmd),
+                _ => {}
             }
 
-            //Save some CPU time
-            std::thread::sleep(Duration::from_millis(10));
+            //Frames are paced by vsync. When nothing was drawn, or the driver
+            //doesn't wait for the screen, save some CPU time
+            if let Some(rest) = MIN_LOOP_TIME.checked_sub(loop_start.elapsed()) {
+                std::thread::sleep(rest);
+            }
         }
-        *self.status.borrow_mut() = PlayerStatus::Stopped;
-        self.clear_background().unwrap();
-        Ok(())
+        audio.stop();
+        drop(audio);
+        drop(video);
+        self.record_history(!finished);
+        self.status.lock().unwrap().stop();
+        self.status_changed();
+        self.redraw_idle();
+        Ok(next)
     }
-}
 
-//Sine wave formula for rainbow cycling background color
-fn rainbow_cycle(i: &mut f32, size: f32) -> (f32, f32, f32, f32) {
-    *i = if (*i + 1.0) % size == 0.0 {
-        0.0
-    } else {
-        *i + 1.0
-    };
-    let red =
-        ((consts::PI / size * 2.0 * *i + 0.0 * consts::PI / 3.0).sin() * 127.0).floor() + 128.0;
-    let green =
-        ((consts::PI / size * 2.0 * *i + 4.0 * consts::PI / 3.0).sin() * 127.0).floor() + 128.0;
-    let blue =
-        ((consts::PI / size * 2.0 * *i + 8.0 * consts::PI / 3.0).sin() * 127.0).floor() + 128.0;
-
-    (red / 255.0, green / 255.0, blue / 255.0, 1.0)
+    //Failing to save history or stats is logged, the song has already been played
+    fn record_history(&self, skipped: bool) {
+        let entry = HistoryEntry::from_status(&self.status.lock().unwrap(), skipped);
+        if let Some(entry) = entry {
+            if let Err(e) = self.stats.lock().unwrap().record_play(&entry) {
+                error!("Failed to record stats: {}", e);
+            }
+            if let Err(e) = self.history.lock().unwrap().record(entry) {
+                error!("Failed to record history: {}", e);
+            }
+        }
+    }
+
+    //Queue estimates depend on the current song, so both are sent
+    fn status_changed(&self) {
+        let status = self.status.lock().unwrap().clone();
+        let queue = self.queue.lock().unwrap().clone();
+        self.broadcast(Event::Status(status.clone()));
+        self.broadcast(Event::Queue { queue, status });
+    }
+
+    fn broadcast(&self, event: Event) {
+        self.events.lock().unwrap().send(&event);
+    }
 }
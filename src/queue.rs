@@ -1,7 +1,230 @@
-use karaoke::collection::Kfile;
-use lazy_static::lazy_static;
-use std::sync::{Arc, Mutex};
+use karaoke::{collection::Kfile, history::HistoryEntry, status::PlayerStatus};
+use serde_derive::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-lazy_static! {
-    pub static ref PLAY_QUEUE: Arc<Mutex<Vec<Kfile>>> = { Arc::from(Mutex::from(Vec::new())) };
+//Song waiting to be played, with the name of whoever requested it. Ids are
+//given out by the worker. Guest is the session that requested it, which is
+//kept private so only that guest can cancel it
+#[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone, Default)]
+pub struct QueueEntry {
+    pub id: u64,
+    pub kfile: Kfile,
+    pub singer: Option<String>,
+    #[serde(skip)]
+    pub guest: Option<u64>,
+}
+
+impl QueueEntry {
+    pub fn new(kfile: Kfile, singer: Option<String>) -> Self {
+        QueueEntry {
+            id: 0,
+            kfile,
+            singer,
+            guest: None,
+        }
+    }
+}
+
+impl From<Kfile> for QueueEntry {
+    fn from(kfile: Kfile) -> Self {
+        QueueEntry::new(kfile, None)
+    }
+}
+
+//Queued song with the number of seconds until it should start, and the unix
+//timestamp it should start at. Unknown once any song ahead has no duration
+#[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone)]
+pub struct QueueEstimate {
+    pub id: u64,
+    pub kfile: Kfile,
+    pub singer: Option<String>,
+    pub wait: Option<u64>,
+    pub wait_minutes: Option<u64>,
+    pub start: Option<u64>,
+}
+
+//Adds up remaining time of the current song and durations of every song ahead,
+//plus changeover_gap seconds between each song
+pub fn estimate_waits(
+    status: &PlayerStatus,
+    queue: &[QueueEntry],
+    changeover_gap: u64,
+) -> Vec<QueueEstimate> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let gap = changeover_gap * 1000;
+
+    //Milliseconds until the next song in queue starts
+    let mut wait = if status.is_playing() {
+        status.remaining().map(|r| r + gap)
+    } else {
+        Some(0)
+    };
+
+    queue
+        .iter()
+        .map(|entry| {
+            let estimate = QueueEstimate {
+                id: entry.id,
+                kfile: entry.kfile.clone(),
+                singer: entry.singer.clone(),
+                wait: wait.map(|w| w / 1000),
+                wait_minutes: wait.map(|w| w.div_ceil(60_000)),
+                start: wait.map(|w| now + w / 1000),
+            };
+            wait = wait.and_then(|w| entry.kfile.expected_duration().map(|d| w + d + gap));
+            estimate
+        })
+        .collect()
+}
+
+//Why a request was turned away, as a repeat or by the queue limits in config,
+//worded for guests
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum QueueError {
+    Repeat(Repeat),
+    QueueFull { limit: usize },
+    SingerLimit { limit: usize },
+    Cooldown { wait: u64 },
+}
+
+impl std::fmt::Display for QueueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            QueueError::Repeat(repeat) => write!(f, "{}", repeat),
+            QueueError::QueueFull { limit } => write!(
+                f,
+                "The queue is full ({} songs), try again once a few have played",
+                limit
+            ),
+            QueueError::SingerLimit { limit } => write!(
+                f,
+                "You already have {} song{} waiting, try again after your turn",
+                limit,
+                if *limit == 1 { "" } else { "s" }
+            ),
+            QueueError::Cooldown { wait } => write!(
+                f,
+                "Please wait {} more second{} before requesting again",
+                wait,
+                if *wait == 1 { "" } else { "s" }
+            ),
+        }
+    }
+}
+
+//Why a song was turned away as a repeat
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum Repeat {
+    Queued,
+    Playing,
+    Played { minutes_ago: u64 },
+}
+
+impl std::fmt::Display for Repeat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Repeat::Queued => write!(f, "Already in the queue"),
+            Repeat::Playing => write!(f, "Playing now"),
+            Repeat::Played { minutes_ago } => write!(f, "Played {} min ago", minutes_ago),
+        }
+    }
+}
+
+//Checks whether kfile is queued, playing, or was played within window minutes
+//of now (unix secs). A window of 0 allows repeats
+pub fn find_repeat(
+    kfile: &Kfile,
+    queue: &[QueueEntry],
+    status: &PlayerStatus,
+    history: &[HistoryEntry],
+    window: u64,
+    now: u64,
+) -> Option<Repeat> {
+    if window == 0 {
+        return None;
+    }
+    let id = kfile.id();
+    if queue.iter().any(|entry| entry.kfile.id() == id) {
+        return Some(Repeat::Queued);
+    }
+    if status.kfile.as_ref().map(Kfile::id) == Some(id) {
+        return Some(Repeat::Playing);
+    }
+
+    let since = now.saturating_sub(window * 60);
+    history
+        .iter()
+        .rev()
+        .find(|entry| entry.song_id == id && entry.started >= since)
+        .map(|entry| Repeat::Played {
+            minutes_ago: now.saturating_sub(entry.started) / 60,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(duration: Option<u64>) -> QueueEntry {
+        QueueEntry::from(Kfile {
+            duration,
+            ..Kfile::default()
+        })
+    }
+
+    #[test]
+    fn test_estimate_waits_idle() {
+        let queue = vec![entry(Some(180_000)), entry(Some(240_000)), entry(None)];
+        let waits: Vec<Option<u64>> = estimate_waits(&PlayerStatus::default(), &queue, 30)
+            .into_iter()
+            .map(|e| e.wait)
+            .collect();
+        assert_eq!(waits, vec![Some(0), Some(210), Some(480)]);
+    }
+
+    #[test]
+    fn test_estimate_waits_playing() {
+        let mut status = PlayerStatus::default();
+        status.start(entry(Some(200_000)));
+        status.position = 80_000;
+        let queue = vec![entry(None), entry(Some(180_000))];
+        let estimates = estimate_waits(&status, &queue, 0);
+        assert_eq!(estimates[0].wait, Some(120));
+        assert_eq!(estimates[0].wait_minutes, Some(2));
+        assert_eq!(estimates[1].wait, None);
+        assert_eq!(estimates[1].start, None);
+    }
+
+    #[test]
+    fn test_find_repeat() {
+        let kfile = Kfile {
+            song: String::from("Ballad"),
+            ..Kfile::default()
+        };
+        let played = HistoryEntry {
+            song_id: kfile.id(),
+            artist: String::new(),
+            song: kfile.song.clone(),
+            singer: None,
+            started: 10_000,
+            played: 0,
+            skipped: false,
+        };
+        let status = PlayerStatus::default();
+        let history = vec![played];
+
+        //Played 20 minutes ago
+        let now = 10_000 + 20 * 60;
+        let repeat = find_repeat(&kfile, &[], &status, &history, 30, now);
+        assert_eq!(repeat, Some(Repeat::Played { minutes_ago: 20 }));
+        assert_eq!(find_repeat(&kfile, &[], &status, &history, 15, now), None);
+        assert_eq!(find_repeat(&kfile, &[], &status, &history, 0, now), None);
+
+        let queue = vec![QueueEntry::from(kfile.clone())];
+        let repeat = find_repeat(&kfile, &queue, &status, &[], 30, now);
+        assert_eq!(repeat, Some(Repeat::Queued));
+    }
 }
@@ -0,0 +1,299 @@
+use failure::bail;
+use image::{GenericImage, RgbaImage};
+use karaoke::collection::Kfile;
+use rodio::Source;
+use std::{
+    fs::{remove_file, File},
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+//Cdg image dimensions, both even so chroma can be subsampled 2x2
+const CDG_WIDTH: u32 = 300;
+const CDG_HEIGHT: u32 = 216;
+
+//Cdg sectors per second
+const SECTOR_RATE: u64 = 75;
+
+//Renders a song to a video file without opening a window. Frames are piped to
+//ffmpeg alongside the decoded audio, or written as raw Y4M + WAV if ffmpeg
+//isn't installed. Lyrics are delayed by offset cdg sectors
+pub fn render(kfile: &Kfile, output: &Path, fps: u64, offset: i64) -> Result<(), failure::Error> {
+    if fps == 0 {
+        bail!("Frame rate must be greater than 0");
+    }
+
+    let wav_path = output.with_extension("wav");
+    println!("Decoding audio: {:?}", kfile.mp3_path);
+    write_wav(&kfile.mp3_path, &wav_path)?;
+
+    if ffmpeg_available() {
+        println!("Encoding video: {:?}", output);
+        let mut ffmpeg = Command::new("ffmpeg")
+            .args([
+                "-y",
+                "-loglevel",
+                "error",
+                "-f",
+                "yuv4mpegpipe",
+                "-i",
+                "-",
+                "-i",
+            ])
+            .arg(&wav_path)
+            .args(["-c:v", "libx264", "-pix_fmt", "yuv420p", "-c:a", "aac"])
+            .arg(output)
+            .stdin(Stdio::piped())
+            .spawn()?;
+        let written = write_y4m(ffmpeg.stdin.take().unwrap(), &kfile.cdg_path, fps, offset);
+        //Wait even if writing failed, ffmpeg still has the wav open
+        let status = ffmpeg.wait();
+        remove_file(&wav_path)?;
+        let frames = written?;
+        if !status?.success() {
+            bail!("ffmpeg failed to encode {:?}", output);
+        }
+        println!("Rendered {} frames to {:?}", frames, output);
+    } else {
+        let y4m_path = output.with_extension("y4m");
+        println!("ffmpeg not found, writing raw video: {:?}", y4m_path);
+        let writer = BufWriter::new(File::create(&y4m_path)?);
+        let frames = write_y4m(writer, &kfile.cdg_path, fps, offset)?;
+        println!(
+            "Rendered {} frames to {:?}, audio to {:?}",
+            frames, y4m_path, wav_path
+        );
+    }
+
+    Ok(())
+}
+
+//Accepts a single cdg / mp3 / extensionless path, or an explicit cdg & mp3 pair
+pub fn kfile_from_inputs(inputs: &[&str]) -> Result<Kfile, failure::Error> {
+    let (cdg_path, mp3_path) = match inputs {
+        [path] => {
+            let path = Path::new(path);
+            (song_file(path, "cdg"), song_file(path, "mp3"))
+        }
+        [first, second] => {
+            let first = PathBuf::from(first);
+            let second = PathBuf::from(second);
+            if first.extension().is_some_and(|e| e == "cdg") {
+                (first, second)
+            } else {
+                (second, first)
+            }
+        }
+        _ => bail!("Expected a song path, or a cdg and mp3 file"),
+    };
+
+    for path in &[&cdg_path, &mp3_path] {
+        if !path.is_file() {
+            bail!("File not found: {:?}", path);
+        }
+    }
+
+    Ok(Kfile {
+        mp3_path,
+        cdg_path,
+        ..Kfile::default()
+    })
+}
+
+//Path with its cdg or mp3 extension swapped for ext. Any other extension is
+//part of the name, ie. "Artist - Mr. Brightside", so ext is added after it
+pub fn song_file(path: &Path, ext: &str) -> PathBuf {
+    let is_song = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("cdg") || e.eq_ignore_ascii_case("mp3"));
+    let mut file = if is_song {
+        path.with_extension("").into_os_string()
+    } else {
+        path.as_os_str().to_os_string()
+    };
+    file.push(".");
+    file.push(ext);
+    PathBuf::from(file)
+}
+
+fn ffmpeg_available() -> bool {
+    Command::new("ffmpeg")
+        .arg("-version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+//Decode mp3 into a 16 bit PCM WAV file
+fn write_wav(mp3_path: &Path, wav_path: &Path) -> Result<(), failure::Error> {
+    let file = File::open(mp3_path)?;
+    let decoder = rodio::Decoder::new(BufReader::new(file))?;
+    let spec = hound::WavSpec {
+        channels: decoder.channels(),
+        sample_rate: decoder.sample_rate(),
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+
+    let mut writer = hound::WavWriter::create(wav_path, spec)?;
+    for sample in decoder {
+        writer.write_sample(sample)?;
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+//Writes a Y4M stream of the cdg at fps, returning the number of frames written.
+//Each frame shows every sector up to its timestamp, delayed by offset sectors
+fn write_y4m<W: Write>(
+    mut writer: W,
+    cdg_path: &Path,
+    fps: u64,
+    offset: i64,
+) -> Result<u64, failure::Error> {
+    let cdg = File::open(cdg_path)?;
+    let mut scsi = cdg::SubchannelStreamIter::new(BufReader::new(cdg));
+    let mut cdg_interp = cdg_renderer::CdgInterpreter::new();
+    let mut cdg_image = RgbaImage::new(CDG_WIDTH, CDG_HEIGHT);
+
+    let header = format!(
+        "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C420jpeg\n",
+        CDG_WIDTH, CDG_HEIGHT, fps
+    );
+    writer.write_all(header.as_bytes())?;
+
+    let mut sector: i64 = 0;
+    let mut frame: u64 = 0;
+    'render: loop {
+        let target_sector = (frame * SECTOR_RATE / fps) as i64 - offset;
+        while sector < target_sector {
+            if let Some(s) = scsi.next() {
+                for cmd in s {
+                    cdg_interp.handle_cmd(cmd);
+                }
+            } else {
+                break 'render;
+            }
+            sector += 1;
+        }
+
+        cdg_image.copy_from(&cdg_interp, 0, 0);
+        writer.write_all(b"FRAME\n")?;
+        writer.write_all(&rgba_to_yuv420(&cdg_image))?;
+        frame += 1;
+    }
+
+    writer.flush()?;
+    Ok(frame)
+}
+
+//Planar BT.601 Y, U, V with chroma averaged over each 2x2 block
+fn rgba_to_yuv420(image: &RgbaImage) -> Vec<u8> {
+    let (width, height) = image.dimensions();
+    let mut y_plane = Vec::with_capacity((width * height) as usize);
+    let mut u_plane = Vec::with_capacity((width * height / 4) as usize);
+    let mut v_plane = Vec::with_capacity((width * height / 4) as usize);
+
+    for pixel in image.pixels() {
+        let [r, g, b, _] = pixel.data;
+        let (r, g, b) = (i32::from(r), i32::from(g), i32::from(b));
+        y_plane.push((((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8);
+    }
+
+    for y in (0..height).step_by(2) {
+        for x in (0..width).step_by(2) {
+            let (mut r, mut g, mut b) = (0, 0, 0);
+            for (dx, dy) in &[(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let [pr, pg, pb, _] = image.get_pixel(x + dx, y + dy).data;
+                r += i32::from(pr);
+                g += i32::from(pg);
+                b += i32::from(pb);
+            }
+            let (r, g, b) = (r / 4, g / 4, b / 4);
+            u_plane.push((((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8);
+            v_plane.push((((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8);
+        }
+    }
+
+    y_plane.extend(u_plane);
+    y_plane.extend(v_plane);
+    y_plane
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+    use std::fs::write;
+
+    #[test]
+    fn test_rgba_to_yuv420() {
+        let white = RgbaImage::from_pixel(4, 2, Rgba { data: [255; 4] });
+        let yuv = rgba_to_yuv420(&white);
+        assert_eq!(yuv.len(), 8 + 2 + 2);
+        assert!(yuv[..8].iter().all(|y| *y == 235));
+        assert!(yuv[8..].iter().all(|c| *c == 128));
+    }
+
+    #[test]
+    fn test_write_y4m() {
+        let cdg_path = PathBuf::from("tests/test_data/render.cdg");
+        write(&cdg_path, vec![0u8; 75 * 96]).unwrap();
+
+        //75 sectors at 25fps, 3 sectors per frame, last frame shows sector 75
+        let mut output = Vec::new();
+        let frames = write_y4m(&mut output, &cdg_path, 25, 0).unwrap();
+        assert_eq!(frames, 26);
+
+        let header = b"YUV4MPEG2 W300 H216 F25:1 Ip A1:1 C420jpeg\n";
+        let frame_size = b"FRAME\n".len() + 300 * 216 * 3 / 2;
+        assert!(output.starts_with(header));
+        assert_eq!(output.len(), header.len() + frame_size * 26);
+
+        remove_file(&cdg_path).unwrap();
+    }
+
+    #[test]
+    fn test_kfile_from_inputs() {
+        let kfile = kfile_from_inputs(&["tests/test_data/songs/match.cdg"]).unwrap();
+        assert_eq!(
+            kfile.mp3_path,
+            PathBuf::from("tests/test_data/songs/match.mp3")
+        );
+
+        let kfile = kfile_from_inputs(&[
+            "tests/test_data/songs/match.mp3",
+            "tests/test_data/songs/sub_songs/match2.cdg",
+        ])
+        .unwrap();
+        assert_eq!(
+            kfile.cdg_path,
+            PathBuf::from("tests/test_data/songs/sub_songs/match2.cdg")
+        );
+
+        assert!(kfile_from_inputs(&["tests/test_data/songs/nomatch"]).is_err());
+    }
+
+    #[test]
+    fn test_song_file_keeps_dots() {
+        let path = Path::new("tests/test_data/Artist - Mr. Dot");
+        let cdg_path = song_file(path, "cdg");
+        let mp3_path = song_file(path, "mp3");
+        assert_eq!(cdg_path, Path::new("tests/test_data/Artist - Mr. Dot.cdg"));
+        assert_eq!(
+            song_file(&cdg_path, "mp4"),
+            Path::new("tests/test_data/Artist - Mr. Dot.mp4")
+        );
+
+        write(&cdg_path, "").unwrap();
+        write(&mp3_path, "").unwrap();
+        let kfile = kfile_from_inputs(&["tests/test_data/Artist - Mr. Dot"]);
+        remove_file(&cdg_path).unwrap();
+        remove_file(&mp3_path).unwrap();
+        assert_eq!(kfile.unwrap().mp3_path, mp3_path);
+    }
+}
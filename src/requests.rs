@@ -0,0 +1,155 @@
+use karaoke::queue::QueueEntry;
+use serde_derive::{Deserialize, Serialize};
+
+//Decided requests kept around so guests can see what happened to theirs
+const DECIDED_KEPT: usize = 100;
+
+#[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestState {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+//Guest request held for the KJ to approve. Requested is a unix timestamp
+#[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone)]
+pub struct SongRequest {
+    pub id: u64,
+    pub entry: QueueEntry,
+    pub requested: u64,
+    pub state: RequestState,
+}
+
+//How a request to add a song was handled
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum Requested {
+    Queued,
+    Pending { id: u64 },
+}
+
+//Requests waiting for approval in approval mode, plus the most recently decided.
+//Requests share the id of their queue entry
+#[derive(Debug, Default)]
+pub struct Requests {
+    requests: Vec<SongRequest>,
+}
+
+impl Requests {
+    pub fn new() -> Self {
+        Requests::default()
+    }
+
+    pub fn add(&mut self, entry: QueueEntry, requested: u64) -> u64 {
+        let id = entry.id;
+        self.requests.push(SongRequest {
+            id,
+            entry,
+            requested,
+            state: RequestState::Pending,
+        });
+        id
+    }
+
+    pub fn all(&self) -> &[SongRequest] {
+        &self.requests
+    }
+
+    //Waiting for approval, oldest first
+    pub fn pending(&self) -> impl Iterator<Item = &SongRequest> {
+        self.requests
+            .iter()
+            .filter(|request| request.state == RequestState::Pending)
+    }
+
+    //Marks a pending request approved, returning the entry to queue
+    pub fn approve(&mut self, id: u64) -> Option<QueueEntry> {
+        let entry = self.pending_mut(id).map(|request| {
+            request.state = RequestState::Approved;
+            request.entry.clone()
+        });
+        self.trim();
+        entry
+    }
+
+    pub fn reject(&mut self, id: u64) -> bool {
+        if let Some(request) = self.pending_mut(id) {
+            request.state = RequestState::Rejected;
+            self.trim();
+            return true;
+        }
+        false
+    }
+
+    //Withdrawn requests are dropped rather than kept as decided
+    pub fn cancel(&mut self, id: u64) -> Option<QueueEntry> {
+        let index = self
+            .requests
+            .iter()
+            .position(|request| request.id == id && request.state == RequestState::Pending)?;
+        Some(self.requests.remove(index).entry)
+    }
+
+    pub fn edit(&mut self, id: u64, singer: Option<String>) -> bool {
+        if let Some(request) = self.pending_mut(id) {
+            request.entry.singer = singer;
+            return true;
+        }
+        false
+    }
+
+    fn pending_mut(&mut self, id: u64) -> Option<&mut SongRequest> {
+        self.requests
+            .iter_mut()
+            .find(|request| request.id == id && request.state == RequestState::Pending)
+    }
+
+    //Drops the oldest decided requests past DECIDED_KEPT
+    fn trim(&mut self) {
+        let decided = self
+            .requests
+            .iter()
+            .filter(|request| request.state != RequestState::Pending)
+            .count();
+        let mut excess = decided.saturating_sub(DECIDED_KEPT);
+        self.requests.retain(|request| {
+            if excess > 0 && request.state != RequestState::Pending {
+                excess -= 1;
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_approve_and_reject() {
+        let mut requests = Requests::new();
+        let entry = |id| QueueEntry {
+            id,
+            ..QueueEntry::default()
+        };
+        let first = requests.add(entry(1), 0);
+        let second = requests.add(entry(2), 0);
+        let third = requests.add(entry(3), 0);
+        assert!(requests.cancel(third).is_some());
+        assert_eq!(requests.pending().count(), 2);
+
+        assert!(requests.edit(first, Some(String::from("Alice"))));
+        let entry = requests.approve(first).unwrap();
+        assert_eq!(entry.singer, Some(String::from("Alice")));
+        assert!(requests.reject(second));
+
+        //Decided requests can't be decided again, but are kept for guests
+        assert_eq!(requests.approve(second), None);
+        assert!(!requests.edit(first, None));
+        assert_eq!(requests.pending().count(), 0);
+        let states: Vec<RequestState> = requests.all().iter().map(|r| r.state).collect();
+        assert_eq!(states, vec![RequestState::Approved, RequestState::Rejected]);
+    }
+}
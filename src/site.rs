@@ -1,27 +1,343 @@
-use actix_web::{error, guard, middleware, web, App, Error, HttpResponse, HttpServer};
-use crossbeam_channel::Sender;
+use actix_files::NamedFile;
+use actix_service::{Service, Transform};
+use actix_session::{CookieSession, Session, UserSession};
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    error, guard, http, middleware, web, App, Error, HttpResponse, HttpServer,
+};
+use bytes::Bytes;
+use futures::{
+    future::{ok, Either, FutureResult},
+    stream, Poll, Stream,
+};
 use karaoke::{
-    channel::{WorkerCommand, WORKER_CHANNEL},
-    collection::{Collection, Kfile, COLLECTION},
-    queue::PLAY_QUEUE,
-    CONFIG,
+    browse::{index_letter, letters, matches, paginate, Letter, Page, PAGE_SIZE},
+    collection::Kfile,
+    config::Config,
+    context::Context,
+    events::Event,
+    history::HistoryEntry,
+    queue::{estimate_waits, QueueEntry, QueueError, QueueEstimate},
+    requests::{Requested, SongRequest},
+    stats::{top_artists, top_songs, window_stats, SongStats, TopArtist, TopSong},
+    status::PlayerStatus,
+    thumbnail::{create_thumbnail, no_thumbnail_path, thumbnail_path},
 };
+use log::error;
 use serde_derive::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex},
-    thread::sleep,
-    time::Duration,
+    net::{IpAddr, UdpSocket},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
+//Songs & artists listed on each most played page
+const TOP_LIMIT: usize = 50;
+
+//Where each frontend library is loaded from when the build didn't bundle it,
+//see ci/fetch_static.sh
+const VENDOR_CDN: &[(&str, &str)] = &[
+    (
+        "bootstrap.min.css",
+        "https://stackpath.bootstrapcdn.com/bootstrap/4.2.1/css/bootstrap.min.css",
+    ),
+    (
+        "bootstrap.min.js",
+        "https://stackpath.bootstrapcdn.com/bootstrap/4.2.1/js/bootstrap.min.js",
+    ),
+    (
+        "jquery.min.js",
+        "https://code.jquery.com/jquery-3.4.0.min.js",
+    ),
+    (
+        "popper.min.js",
+        "https://cdnjs.cloudflare.com/ajax/libs/popper.js/1.14.6/umd/popper.min.js",
+    ),
+];
+
+//Session key set once the admin password has been entered
+const ADMIN_KEY: &str = "admin";
+
+//Session keys identifying a guest, and the name they sing under
+const GUEST_KEY: &str = "guest";
+const NAME_KEY: &str = "name";
+
+//Admins can use playback controls. Anyone is an admin if no password is set
+fn is_admin(session: &Session, config: &Config) -> bool {
+    config.admin_password.is_empty()
+        || session
+            .get::<bool>(ADMIN_KEY)
+            .unwrap_or(None)
+            .unwrap_or(false)
+}
+
+//Compares every byte whatever the first difference, so response times don't
+//give away how much of a password was right
+fn same_password(given: &str, expected: &str) -> bool {
+    let (given, expected) = (given.as_bytes(), expected.as_bytes());
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+//Id for the guest's session, made on their first visit that needs one
+fn guest_id(session: &Session) -> Result<u64, Error> {
+    match session.get::<u64>(GUEST_KEY)? {
+        Some(guest) => Ok(guest),
+        None => {
+            let guest = rand::random();
+            session.set(GUEST_KEY, guest)?;
+            Ok(guest)
+        }
+    }
+}
+
+fn guest_name(session: &Session) -> Option<String> {
+    session.get::<String>(NAME_KEY).unwrap_or(None)
+}
+
+//Turns away guests from admin routes. Pages redirect to the login page, api
+//calls are refused
+#[derive(Clone)]
+pub struct AdminOnly;
+
+impl<S, B> Transform<S> for AdminOnly
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AdminOnlyMiddleware<S>;
+    type Future = FutureResult<Self::Transform, Self::InitError>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AdminOnlyMiddleware { service })
+    }
+}
+
+pub struct AdminOnlyMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for AdminOnlyMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, FutureResult<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.service.poll_ready()
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let admin = match req.app_data::<Context>() {
+            Some(context) => is_admin(&req.get_session(), &context.config),
+            None => false,
+        };
+        if admin {
+            return Either::A(self.service.call(req));
+        }
+
+        let refused: Error = if req.path().starts_with("/api/") {
+            error::ErrorForbidden("Admin login required")
+        } else {
+            let login = HttpResponse::Found().header("location", "/login").finish();
+            error::InternalError::from_response("", login).into()
+        };
+        Either::B(ok(req.error_response(refused)))
+    }
+}
+
 #[derive(Deserialize)]
 struct Song {
     hash: u64,
+    #[serde(default)]
+    singer: Option<String>,
+    //Queue even if the song was played or queued recently
+    #[serde(default)]
+    force: bool,
+}
+
+impl Song {
+    fn singer(&self) -> Option<String> {
+        singer_name(&self.singer)
+    }
+}
+
+//Blank names are treated as no name given
+fn singer_name(singer: &Option<String>) -> Option<String> {
+    singer
+        .as_ref()
+        .map(|singer| singer.trim().to_string())
+        .filter(|singer| !singer.is_empty())
+}
+
+#[derive(Deserialize)]
+struct Login {
+    password: String,
+}
+
+//Queue positions, counted from 0
+#[derive(Deserialize)]
+struct Move {
+    from: usize,
+    to: usize,
+}
+
+#[derive(Deserialize)]
+struct Pause {
+    paused: bool,
+}
+
+//Percentage, higher is turned down to 100
+#[derive(Deserialize)]
+struct Volume {
+    volume: u8,
+}
+
+//Semitones, further than player::MAX_KEY is turned back to it
+#[derive(Deserialize)]
+struct Key {
+    key: i8,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Role {
+    admin: bool,
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Guest {
+    #[serde(default)]
+    name: Option<String>,
+}
+
+//Songs the guest has queued, with their place in the queue counted from 0,
+//and their requests in approval mode
+#[derive(Serialize, Deserialize)]
+struct MySongs {
+    queue: Vec<MyQueued>,
+    requests: Vec<SongRequest>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MyQueued {
+    position: usize,
+    #[serde(flatten)]
+    estimate: QueueEstimate,
+}
+
+#[derive(Deserialize)]
+struct Singer {
+    #[serde(default)]
+    singer: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SongRow {
+    id: u64,
+    kfile: Kfile,
+    stats: SongStats,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ArtistPage {
+    name: String,
+    songs: Vec<SongRow>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ArtistRow {
+    hash: u64,
+    name: String,
+    num_songs: usize,
+}
+
+//Page, starting letter, search text and order for browsing songs or artists.
+//Sort is by name unless sort=plays is given, which puts the most played first
+#[derive(Deserialize)]
+struct Browse {
+    page: Option<usize>,
+    letter: Option<String>,
+    q: Option<String>,
+    sort: Option<String>,
+}
+
+impl Browse {
+    fn query(&self) -> Option<String> {
+        singer_name(&self.q)
+    }
+}
+
+//Page of songs or artists, with what was asked for so links can keep it
+#[derive(Serialize, Deserialize)]
+struct Listing<T> {
+    path: String,
+    #[serde(flatten)]
+    page: Page<T>,
+    letter: Option<String>,
+    q: Option<String>,
+    sort: Option<String>,
+    letters: Vec<Letter>,
+}
+
+//Days to count plays over, all time if not given
+#[derive(Deserialize)]
+struct TopQuery {
+    days: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Top {
+    days: Option<u64>,
+    windows: Vec<u64>,
+    songs: Vec<TopSong>,
+    artists: Vec<TopArtist>,
 }
 
 #[derive(Serialize, Deserialize)]
 struct Queue {
-    queue: Vec<Kfile>,
+    status: PlayerStatus,
+    queue: Vec<QueueEstimate>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct HistoryList {
+    history: Vec<HistoryEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RequestList {
+    approval_mode: bool,
+    requests: Vec<SongRequest>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Position {
+    position: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CollectionSize {
+    songs: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PlaybackError {
+    song: String,
+    message: String,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -29,106 +345,637 @@ struct JsonStatus {
     status: &'static str,
 }
 
-fn index(tera: web::Data<tera::Tera>) -> Result<HttpResponse, Error> {
-    let context = HashMap::<String, u64>::new();
+#[derive(Serialize, Deserialize)]
+struct JsonPending {
+    status: &'static str,
+    id: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonError {
+    status: &'static str,
+    message: String,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn index(tera: web::Data<tera::Tera>, context: web::Data<Context>) -> Result<HttpResponse, Error> {
+    let status = context.status.lock().unwrap().clone();
     let html = tera
-        .render("index.html", &context)
+        .render("index.html", &status)
         .map_err(|_| error::ErrorInternalServerError("Template error"))?;
     Ok(HttpResponse::Ok().content_type("text/html").body(html))
 }
 
+//Sorts rows by name, or by plays if asked and the rows have them, keeps those
+//under the letter & matching the search, then picks out the page asked for
+fn get_listing<T>(
+    path: &str,
+    mut rows: Vec<T>,
+    browse: &Browse,
+    name: impl Fn(&T) -> &str,
+    searched: impl Fn(&T, &str) -> bool,
+    plays: Option<fn(&T) -> u64>,
+) -> Listing<T> {
+    rows.sort_by_cached_key(|row| name(row).to_lowercase());
+    let letters = letters(rows.iter().map(&name));
+
+    let sort = browse.sort.as_ref().filter(|sort| sort.as_str() == "plays");
+    let plays = plays.filter(|_| sort.is_some());
+    if let Some(plays) = plays {
+        //Stable, so equal plays stay by name
+        rows.sort_by_key(|row| std::cmp::Reverse(plays(row)));
+    }
+
+    let letter = browse
+        .letter
+        .as_ref()
+        .filter(|letter| !letter.is_empty())
+        .map(|letter| index_letter(letter));
+    if let Some(letter) = &letter {
+        rows.retain(|row| &index_letter(name(row)) == letter);
+    }
+    let q = browse.query();
+    if let Some(q) = &q {
+        rows.retain(|row| searched(row, q));
+    }
+
+    Listing {
+        path: path.to_string(),
+        page: paginate(rows, browse.page.unwrap_or(1), PAGE_SIZE),
+        letter,
+        q,
+        sort: plays.map(|_| String::from("plays")),
+        letters,
+    }
+}
+
+//Songs with their all time stats by song name or plays. Search looks at song & artist
+fn get_songs(context: &Context, browse: &Browse) -> Listing<SongRow> {
+    let collection = context.collection.read().unwrap();
+    let stats = context.stats.lock().unwrap();
+    let songs: Vec<SongRow> = collection
+        .by_song
+        .iter()
+        .map(|(id, kfile)| SongRow {
+            id: *id,
+            kfile: kfile.clone(),
+            stats: stats.get(*id),
+        })
+        .collect();
+    get_listing(
+        "/songs",
+        songs,
+        browse,
+        |row| &row.kfile.song,
+        |row, q| matches(q, &[row.kfile.song.as_str(), row.kfile.artist.as_str()]),
+        Some(|row| row.stats.plays()),
+    )
+}
+
+fn get_artists(context: &Context, browse: &Browse) -> Listing<ArtistRow> {
+    let collection = context.collection.read().unwrap();
+    let artists: Vec<ArtistRow> = collection
+        .by_artist
+        .iter()
+        .map(|(hash, artist)| ArtistRow {
+            hash: *hash,
+            name: artist.name.clone(),
+            num_songs: artist.num_songs,
+        })
+        .collect();
+    get_listing(
+        "/artists",
+        artists,
+        browse,
+        |row| &row.name,
+        |row, q| matches(q, &[row.name.as_str()]),
+        None,
+    )
+}
+
 fn songs(
     tera: web::Data<tera::Tera>,
-    collection: web::Data<Collection>,
+    browse: web::Query<Browse>,
+    context: web::Data<Context>,
 ) -> Result<HttpResponse, Error> {
+    let songs = get_songs(&context, &browse);
     let html = tera
-        .render("songs.html", collection.get_ref())
+        .render("songs.html", &songs)
         .map_err(|_| error::ErrorInternalServerError("Template error"))?;
     Ok(HttpResponse::Ok().content_type("text/html").body(html))
 }
 
+fn api_songs(browse: web::Query<Browse>, context: web::Data<Context>) -> HttpResponse {
+    HttpResponse::Ok().json(get_songs(&context, &browse))
+}
+
 fn artists(
     tera: web::Data<tera::Tera>,
-    collection: web::Data<Collection>,
+    browse: web::Query<Browse>,
+    context: web::Data<Context>,
 ) -> Result<HttpResponse, Error> {
+    let artists = get_artists(&context, &browse);
     let html = tera
-        .render("artists.html", collection.get_ref())
+        .render("artists.html", &artists)
         .map_err(|_| error::ErrorInternalServerError("Template error"))?;
     Ok(HttpResponse::Ok().content_type("text/html").body(html))
 }
 
+fn api_artists(browse: web::Query<Browse>, context: web::Data<Context>) -> HttpResponse {
+    HttpResponse::Ok().json(get_artists(&context, &browse))
+}
+
 fn artist(
     tera: web::Data<tera::Tera>,
     hash: web::Path<u64>,
-    collection: web::Data<Collection>,
+    context: web::Data<Context>,
 ) -> Result<HttpResponse, Error> {
-    let artist = collection.by_artist.get(&hash);
+    let collection = context.collection.read().unwrap();
+    let artist = collection
+        .by_artist
+        .get(&hash)
+        .ok_or_else(|| error::ErrorNotFound("Artist not found"))?;
+    let stats = context.stats.lock().unwrap();
+    let mut songs: Vec<SongRow> = artist
+        .songs
+        .iter()
+        .map(|(id, kfile)| SongRow {
+            id: *id,
+            kfile: kfile.clone(),
+            stats: stats.get(*id),
+        })
+        .collect();
+    songs.sort_by_cached_key(|row| row.kfile.song.to_lowercase());
+    let page = ArtistPage {
+        name: artist.name.clone(),
+        songs,
+    };
     let html = tera
-        .render("artist.html", &artist)
+        .render("artist.html", &page)
         .map_err(|_| error::ErrorInternalServerError("Template error"))?;
     Ok(HttpResponse::Ok().content_type("text/html").body(html))
 }
 
-fn queue(
-    tera: web::Data<tera::Tera>,
-    queue: web::Data<Arc<Mutex<Vec<Kfile>>>>,
-) -> Result<HttpResponse, Error> {
-    let _queue = queue.lock().unwrap();
+fn get_queue(context: &Context) -> Queue {
+    let _queue = context.queue.lock().unwrap();
     let queue = _queue.clone();
     drop(_queue);
+    let status = context.status.lock().unwrap().clone();
+
+    queue_estimates(context, &queue, status)
+}
 
-    let queue = Queue { queue };
+fn queue_estimates(context: &Context, queue: &[QueueEntry], status: PlayerStatus) -> Queue {
+    let queue = estimate_waits(&status, queue, context.config.changeover_gap);
+    Queue { status, queue }
+}
+
+fn queue(tera: web::Data<tera::Tera>, context: web::Data<Context>) -> Result<HttpResponse, Error> {
+    let queue = get_queue(&context);
     let html = tera
         .render("queue.html", &queue)
         .map_err(|_| error::ErrorInternalServerError("Template error"))?;
     Ok(HttpResponse::Ok().content_type("text/html").body(html))
 }
 
+fn api_queue(context: web::Data<Context>) -> HttpResponse {
+    HttpResponse::Ok().json(get_queue(&context))
+}
+
+//All time counters, or plays counted from history over the last few days
+fn get_top(context: &Context, days: Option<u64>) -> Top {
+    let stats = match days {
+        Some(days) => {
+            let since = now().saturating_sub(days * 24 * 60 * 60);
+            window_stats(context.history.lock().unwrap().entries(), since)
+        }
+        None => context.stats.lock().unwrap().all().clone(),
+    };
+
+    let collection = context.collection.read().unwrap();
+    Top {
+        days,
+        windows: context.config.top_windows.clone(),
+        songs: top_songs(&collection, &stats, TOP_LIMIT),
+        artists: top_artists(&collection, &stats, TOP_LIMIT),
+    }
+}
+
+fn top(
+    tera: web::Data<tera::Tera>,
+    query: web::Query<TopQuery>,
+    context: web::Data<Context>,
+) -> Result<HttpResponse, Error> {
+    let top = get_top(&context, query.days);
+    let html = tera
+        .render("top.html", &top)
+        .map_err(|_| error::ErrorInternalServerError("Template error"))?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+}
+
+fn api_top(query: web::Query<TopQuery>, context: web::Data<Context>) -> HttpResponse {
+    HttpResponse::Ok().json(get_top(&context, query.days))
+}
+
+//Most recently played first
+fn get_history(context: &Context) -> HistoryList {
+    let history = context.history.lock().unwrap().recent();
+    HistoryList { history }
+}
+
+fn history(
+    tera: web::Data<tera::Tera>,
+    context: web::Data<Context>,
+) -> Result<HttpResponse, Error> {
+    let history = get_history(&context);
+    let html = tera
+        .render("history.html", &history)
+        .map_err(|_| error::ErrorInternalServerError("Template error"))?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+}
+
+fn api_history(context: web::Data<Context>) -> HttpResponse {
+    HttpResponse::Ok().json(get_history(&context))
+}
+
+//Full history oldest first, for royalty reporting
+fn api_history_csv(context: web::Data<Context>) -> Result<HttpResponse, Error> {
+    let csv = context
+        .history
+        .lock()
+        .unwrap()
+        .to_csv()
+        .map_err(|_| error::ErrorInternalServerError("History export error"))?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv")
+        .header(
+            "Content-Disposition",
+            "attachment; filename=\"history.csv\"",
+        )
+        .body(csv))
+}
+
+fn api_status(context: web::Data<Context>) -> HttpResponse {
+    let status = context.status.lock().unwrap().clone();
+    HttpResponse::Ok().json(status)
+}
+
+//Server-sent event stream of queue, player & collection changes
+fn event_stream(context: web::Data<Context>) -> HttpResponse {
+    let receiver = context.events.lock().unwrap().new_client();
+
+    //Current state is sent first, so pages are up to date however long ago they were rendered
+    let status = context.status.lock().unwrap().clone();
+    let queue = context.queue.lock().unwrap().clone();
+    let requests = context.requests.lock().unwrap().all().to_vec();
+    let initial = vec![
+        Event::Status(status.clone()),
+        Event::Queue { queue, status },
+        Event::Requests(requests),
+    ];
+
+    let stream = stream::iter_ok(initial)
+        .chain(receiver)
+        .map(move |event| event_bytes(&context, event))
+        .map_err(|_| error::ErrorInternalServerError("Event stream closed"));
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .streaming(stream)
+}
+
+fn event_bytes(context: &Context, event: Event) -> Bytes {
+    let (name, data) = match event {
+        Event::Queue { queue, status } => (
+            "queue",
+            serde_json::to_string(&queue_estimates(context, &queue, status)),
+        ),
+        Event::Status(status) => ("status", serde_json::to_string(&status)),
+        Event::Position(position) => ("position", serde_json::to_string(&Position { position })),
+        Event::Collection { songs } => (
+            "collection",
+            serde_json::to_string(&CollectionSize { songs }),
+        ),
+        Event::Requests(requests) => (
+            "requests",
+            serde_json::to_string(&RequestList {
+                approval_mode: context.config.approval_mode,
+                requests,
+            }),
+        ),
+        //Named to avoid clashing with EventSource's own connection error event
+        Event::PlaybackError { song, message } => (
+            "playback_error",
+            serde_json::to_string(&PlaybackError { song, message }),
+        ),
+    };
+    Bytes::from(format!(
+        "event: {}\ndata: {}\n\n",
+        name,
+        data.unwrap_or_default()
+    ))
+}
+
+//Serves a song's thumbnail, rendering it first if it doesn't exist yet.
+//Rendering reads the cdg, so is done without holding the collection
+fn thumbnail(hash: web::Path<u64>, context: web::Data<Context>) -> Result<NamedFile, Error> {
+    let kfile = find_song(&context, *hash)?;
+
+    let path = thumbnail_path(&context.config.data_path, *hash);
+    if no_thumbnail_path(&context.config.data_path, *hash).exists() {
+        return Err(error::ErrorNotFound("Song has no thumbnail"));
+    }
+    if !path.exists() {
+        let created = create_thumbnail(&kfile, &path)
+            .map_err(|_| error::ErrorInternalServerError("Thumbnail error"))?;
+        if !created {
+            return Err(error::ErrorNotFound("Song has no thumbnail"));
+        }
+    }
+
+    Ok(NamedFile::open(path)?)
+}
+
+//Bundled copy of a frontend library, or its CDN if the build left it out
+fn vendor(
+    file: web::Path<String>,
+    context: web::Data<Context>,
+) -> Result<actix_web::Either<NamedFile, HttpResponse>, Error> {
+    let (name, cdn) = VENDOR_CDN
+        .iter()
+        .find(|(name, _)| *name == file.as_str())
+        .ok_or_else(|| error::ErrorNotFound("File not found"))?;
+    let path = context.config.data_path.join("static/vendor").join(name);
+    if path.exists() {
+        return Ok(actix_web::Either::A(NamedFile::open(path)?));
+    }
+    Ok(actix_web::Either::B(
+        HttpResponse::Found()
+            .header(http::header::LOCATION, *cdn)
+            .finish(),
+    ))
+}
+
+//Worker thread isn't answering, nothing the guest can do about it
+fn worker_error(e: failure::Error) -> Error {
+    error!("{}", e);
+    error::ErrorInternalServerError("Worker error")
+}
+
+//Entry for a song added by the guest, sung under their session name unless
+//another is given
+//Hash may be from a page loaded before the song was removed by a refresh
+fn find_song(context: &Context, hash: u64) -> Result<Kfile, Error> {
+    context
+        .collection
+        .read()
+        .unwrap()
+        .by_song
+        .get(&hash)
+        .cloned()
+        .ok_or_else(|| error::ErrorNotFound("Song not found"))
+}
+
+fn guest_entry(kfile: Kfile, form: &Song, session: &Session) -> Result<QueueEntry, Error> {
+    let mut entry = QueueEntry::new(kfile, form.singer().or_else(|| guest_name(session)));
+    entry.guest = Some(guest_id(session)?);
+    Ok(entry)
+}
+
+//Only admins can add a repeat anyway, and their songs skip approval mode
 fn add(
     form: web::Form<Song>,
-    collection: web::Data<Collection>,
-    worker_sender: web::Data<Sender<WorkerCommand>>,
-) -> HttpResponse {
-    let hash = form.hash;
-    let kfile = collection.by_song[&hash].clone();
-    let cmd = WorkerCommand::AddQueue { kfile };
-    worker_sender.send(cmd).unwrap();
-    HttpResponse::Ok().json(JsonStatus { status: "ok" })
+    session: Session,
+    context: web::Data<Context>,
+) -> Result<HttpResponse, Error> {
+    let kfile = find_song(&context, form.hash)?;
+    let admin = is_admin(&session, &context.config);
+    let entry = guest_entry(kfile, &form, &session)?;
+    let added = context
+        .add_queue(entry, admin, form.force && admin)
+        .map_err(worker_error)?;
+    Ok(match added {
+        Ok(Requested::Queued) => HttpResponse::Ok().json(JsonStatus { status: "ok" }),
+        Ok(Requested::Pending { id }) => HttpResponse::Ok().json(JsonPending {
+            status: "pending",
+            id,
+        }),
+        Err(e @ QueueError::Repeat(_)) => HttpResponse::Conflict().json(JsonError {
+            status: "repeat",
+            message: e.to_string(),
+        }),
+        Err(e) => HttpResponse::build(http::StatusCode::TOO_MANY_REQUESTS).json(JsonError {
+            status: "limit",
+            message: e.to_string(),
+        }),
+    })
 }
 
 fn playnow(
     form: web::Form<Song>,
-    collection: web::Data<Collection>,
-    worker_sender: web::Data<Sender<WorkerCommand>>,
+    session: Session,
+    context: web::Data<Context>,
+) -> Result<HttpResponse, Error> {
+    let kfile = find_song(&context, form.hash)?;
+    context.play_now(guest_entry(kfile, &form, &session)?);
+    Ok(HttpResponse::Ok().json(JsonStatus { status: "ok" }))
+}
+
+fn get_my_songs(context: &Context, guest: u64) -> MySongs {
+    let queue = context.queue.lock().unwrap().clone();
+    let status = context.status.lock().unwrap().clone();
+    let estimates = estimate_waits(&status, &queue, context.config.changeover_gap);
+    let queue = queue
+        .iter()
+        .zip(estimates)
+        .enumerate()
+        .filter(|(_, (entry, _))| entry.guest == Some(guest))
+        .map(|(position, (_, estimate))| MyQueued { position, estimate })
+        .collect();
+
+    let requests = context
+        .requests
+        .lock()
+        .unwrap()
+        .all()
+        .iter()
+        .filter(|request| request.entry.guest == Some(guest))
+        .cloned()
+        .collect();
+    MySongs { queue, requests }
+}
+
+fn api_my_songs(session: Session, context: web::Data<Context>) -> Result<HttpResponse, Error> {
+    let guest = guest_id(&session)?;
+    Ok(HttpResponse::Ok().json(get_my_songs(&context, guest)))
+}
+
+//Guests can cancel their own queued songs & pending requests, admins anyone's
+fn cancel(
+    id: web::Path<u64>,
+    session: Session,
+    context: web::Data<Context>,
+) -> Result<HttpResponse, Error> {
+    let guest = if is_admin(&session, &context.config) {
+        None
+    } else {
+        Some(guest_id(&session)?)
+    };
+    if context.cancel_entry(*id, guest).map_err(worker_error)? {
+        Ok(HttpResponse::Ok().json(JsonStatus { status: "ok" }))
+    } else {
+        Ok(HttpResponse::NotFound().json(JsonError {
+            status: "error",
+            message: String::from("Song isn't yours or has already played"),
+        }))
+    }
+}
+
+//Sets the name the guest's songs are sung under
+fn set_guest(form: web::Form<Guest>, session: Session) -> Result<HttpResponse, Error> {
+    match singer_name(&form.name) {
+        Some(name) => session.set(NAME_KEY, name)?,
+        None => session.remove(NAME_KEY),
+    }
+    guest_id(&session)?;
+    Ok(HttpResponse::Ok().json(JsonStatus { status: "ok" }))
+}
+
+fn get_requests(context: &Context) -> RequestList {
+    let requests = context.requests.lock().unwrap().all().to_vec();
+    RequestList {
+        approval_mode: context.config.approval_mode,
+        requests,
+    }
+}
+
+//Pending requests for the KJ to approve, reject or edit
+fn admin_requests(
+    tera: web::Data<tera::Tera>,
+    context: web::Data<Context>,
+) -> Result<HttpResponse, Error> {
+    let requests = get_requests(&context);
+    let html = tera
+        .render("requests.html", &requests)
+        .map_err(|_| error::ErrorInternalServerError("Template error"))?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+}
+
+fn api_requests(context: web::Data<Context>) -> HttpResponse {
+    HttpResponse::Ok().json(get_requests(&context))
+}
+
+fn approve_request(id: web::Path<u64>, context: web::Data<Context>) -> HttpResponse {
+    context.approve_request(*id);
+    HttpResponse::Ok().json(JsonStatus { status: "ok" })
+}
+
+fn reject_request(id: web::Path<u64>, context: web::Data<Context>) -> HttpResponse {
+    context.reject_request(*id);
+    HttpResponse::Ok().json(JsonStatus { status: "ok" })
+}
+
+fn edit_request(
+    id: web::Path<u64>,
+    form: web::Form<Singer>,
+    context: web::Data<Context>,
 ) -> HttpResponse {
-    let hash = form.hash;
-    let kfile = collection.by_song[&hash].clone();
-    let cmd = WorkerCommand::PlayNow { kfile };
-    worker_sender.send(cmd).unwrap();
+    context.edit_request(*id, singer_name(&form.singer));
+    HttpResponse::Ok().json(JsonStatus { status: "ok" })
+}
+
+fn move_queue(form: web::Form<Move>, context: web::Data<Context>) -> HttpResponse {
+    context.move_queue(form.from, form.to);
     HttpResponse::Ok().json(JsonStatus { status: "ok" })
 }
 
-fn next(worker_sender: web::Data<Sender<WorkerCommand>>) -> HttpResponse {
-    let cmd = WorkerCommand::Next;
-    worker_sender.send(cmd).unwrap();
-    sleep(Duration::from_millis(500));
+fn pause(form: web::Form<Pause>, context: web::Data<Context>) -> HttpResponse {
+    context.pause(form.paused);
     HttpResponse::Ok().json(JsonStatus { status: "ok" })
 }
 
-fn clear(worker_sender: web::Data<Sender<WorkerCommand>>) -> HttpResponse {
-    let cmd = WorkerCommand::ClearQueue;
-    worker_sender.send(cmd).unwrap();
-    sleep(Duration::from_millis(500));
+fn volume(form: web::Form<Volume>, context: web::Data<Context>) -> HttpResponse {
+    context.set_volume(form.volume);
     HttpResponse::Ok().json(JsonStatus { status: "ok" })
 }
 
-fn stop(worker_sender: web::Data<Sender<WorkerCommand>>) -> HttpResponse {
-    let cmd = WorkerCommand::Stop;
-    worker_sender.send(cmd).unwrap();
-    sleep(Duration::from_millis(500));
+fn key(form: web::Form<Key>, context: web::Data<Context>) -> HttpResponse {
+    context.set_key(form.key);
     HttpResponse::Ok().json(JsonStatus { status: "ok" })
 }
 
+fn login_page(tera: web::Data<tera::Tera>) -> Result<HttpResponse, Error> {
+    let context = HashMap::<String, u64>::new();
+    let html = tera
+        .render("login.html", &context)
+        .map_err(|_| error::ErrorInternalServerError("Template error"))?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+}
+
+fn login(
+    form: web::Form<Login>,
+    session: Session,
+    context: web::Data<Context>,
+) -> Result<HttpResponse, Error> {
+    if context.config.admin_password.is_empty()
+        || !same_password(&form.password, &context.config.admin_password)
+    {
+        return Ok(HttpResponse::Unauthorized().json(JsonError {
+            status: "error",
+            message: String::from("Wrong password"),
+        }));
+    }
+    session.set(ADMIN_KEY, true)?;
+    Ok(HttpResponse::Ok().json(JsonStatus { status: "ok" }))
+}
+
+fn logout(session: Session) -> HttpResponse {
+    session.remove(ADMIN_KEY);
+    HttpResponse::Ok().json(JsonStatus { status: "ok" })
+}
+
+//Lets pages show controls for the current role
+fn api_role(session: Session, context: web::Data<Context>) -> HttpResponse {
+    HttpResponse::Ok().json(Role {
+        admin: is_admin(&session, &context.config),
+        name: guest_name(&session),
+    })
+}
+
+fn next(context: web::Data<Context>) -> HttpResponse {
+    context.next();
+    HttpResponse::Ok().json(JsonStatus { status: "ok" })
+}
+
+fn clear(context: web::Data<Context>) -> HttpResponse {
+    context.clear_queue();
+    HttpResponse::Ok().json(JsonStatus { status: "ok" })
+}
+
+fn stop(context: web::Data<Context>) -> HttpResponse {
+    context.stop();
+    HttpResponse::Ok().json(JsonStatus { status: "ok" })
+}
+
+//Scanning can take a while on big collections, so it runs in the background
+//and guests get a collection event when it's done
+fn refresh(context: web::Data<Context>) -> HttpResponse {
+    if context.spawn_refresh() {
+        HttpResponse::Accepted().json(JsonStatus { status: "ok" })
+    } else {
+        HttpResponse::Conflict().json(JsonError {
+            status: "error",
+            message: String::from("Already refreshing"),
+        })
+    }
+}
+
 fn p404(tera: web::Data<tera::Tera>) -> Result<HttpResponse, Error> {
     let context = HashMap::<String, u64>::new();
     let html = tera
@@ -139,48 +986,220 @@ fn p404(tera: web::Data<tera::Tera>) -> Result<HttpResponse, Error> {
         .body(html))
 }
 
-fn get_server_port() -> u16 {
+pub fn get_server_port() -> u16 {
     std::env::var("PORT")
         .ok()
         .and_then(|p| p.parse().ok())
         .unwrap_or(8080)
 }
 
-pub fn run() -> std::io::Result<()> {
-    std::env::set_var("RUST_LOG", "actix_web=debug");
-    env_logger::init();
+//Address guests on the local network can reach the site from. Connecting a UDP
+//socket doesn't send any packets, but lets the OS pick the outbound interface.
+//Offline venues often have no default route, so then any interface's address
+//is used. None without a network guests could be on
+pub fn get_lan_url() -> Option<String> {
+    let routed = UdpSocket::bind("0.0.0.0:0")
+        .and_then(|socket| {
+            socket.connect("8.8.8.8:80")?;
+            socket.local_addr()
+        })
+        .map(|addr| addr.ip())
+        .ok();
+    let ip = routed.into_iter().chain(interface_ips()).find(reachable)?;
+    Some(format!("http://{}:{}", ip, get_server_port()))
+}
+
+//Addresses other devices could reach, not loopback or the link local address
+//an interface gives itself when no network hands one out
+fn reachable(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => !ip.is_loopback() && !ip.is_unspecified() && !ip.is_link_local(),
+        IpAddr::V6(_) => false,
+    }
+}
+
+#[cfg(unix)]
+fn interface_ips() -> Vec<IpAddr> {
+    use nix::{ifaddrs::getifaddrs, sys::socket::SockAddr};
+    getifaddrs()
+        .map(|interfaces| {
+            interfaces
+                .filter_map(|interface| match interface.address {
+                    Some(SockAddr::Inet(addr)) => Some(addr.to_std().ip()),
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(windows)]
+fn interface_ips() -> Vec<IpAddr> {
+    use ipconfig::{get_adapters, OperStatus};
+    get_adapters()
+        .map(|adapters| {
+            adapters
+                .iter()
+                .filter(|adapter| adapter.oper_status() == OperStatus::IfOperStatusUp)
+                .flat_map(|adapter| adapter.ip_addresses().to_vec())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(not(any(unix, windows)))]
+fn interface_ips() -> Vec<IpAddr> {
+    Vec::new()
+}
+
+//Built in templates, with any of the same name in the theme dir used instead
+pub fn load_templates(data_path: &Path) -> tera::Tera {
+    let template_path = data_path.join("templates/**/*");
+    let mut tera = tera::Tera::new(template_path.to_str().unwrap()).unwrap();
+
+    let theme_path = data_path.join("theme");
+    let pattern = theme_path.join("**/*.html");
+    let theme: Vec<(_, String)> = glob::glob(pattern.to_str().unwrap())
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .filter_map(|path| {
+            let name = path.strip_prefix(&theme_path).ok()?;
+            let name = name.to_str()?.replace('\\', "/");
+            Some((path, name))
+        })
+        .collect();
+    let files = theme
+        .iter()
+        .map(|(path, name)| (path, Some(name.as_str())))
+        .collect();
+    if let Err(e) = tera.add_template_files(files) {
+        error!(
+            "Failed to load theme from {:?}, using built in templates: {}",
+            theme_path, e
+        );
+        tera = tera::Tera::new(template_path.to_str().unwrap()).unwrap();
+    }
+    tera
+}
 
+pub fn run(context: Context) -> std::io::Result<()> {
     let port = get_server_port();
     let addr = std::net::SocketAddr::from(([0, 0, 0, 0], port));
 
-    let server = HttpServer::new(|| {
-        let collection = COLLECTION.clone();
-        let worker_sender = WORKER_CHANNEL.0.clone();
-        let play_queue = PLAY_QUEUE.clone();
+    //Sessions are signed with a key made fresh each run, so admins log in and
+    //guests enter their name again after a restart
+    let session_key: [u8; 32] = rand::random();
+    if context.config.admin_password.is_empty() {
+        println!("No admin password set, anyone can use playback controls");
+    }
 
-        let mut template_path = CONFIG.data_path.clone();
-        template_path.push("templates/**/*");
-        let tera = tera::Tera::new(template_path.to_str().unwrap()).unwrap();
+    let server = HttpServer::new(move || {
+        let tera = load_templates(&context.config.data_path);
 
-        let mut static_path = CONFIG.data_path.clone();
+        let mut static_path = context.config.data_path.clone();
         static_path.push("static");
 
         App::new()
-            .data(collection)
-            .data(worker_sender)
-            .data(play_queue)
+            .data(context.clone())
             .data(tera)
+            .wrap(
+                CookieSession::signed(&session_key)
+                    .name("karaoke")
+                    .secure(false),
+            )
             .wrap(middleware::Logger::default()) // enable logger
             .service(web::resource("/").route(web::get().to(index)))
             .service(web::resource("/songs").route(web::get().to(songs)))
             .service(web::resource("/artists").route(web::get().to(artists)))
             .service(web::resource("/artist/{hash}").route(web::get().to(artist)))
             .service(web::resource("/queue").route(web::get().to(queue)))
+            .service(web::resource("/history").route(web::get().to(history)))
+            .service(web::resource("/top").route(web::get().to(top)))
+            .service(web::resource("/login").route(web::get().to(login_page)))
+            .service(
+                web::resource("/admin/requests")
+                    .wrap(AdminOnly)
+                    .route(web::get().to(admin_requests)),
+            )
+            .service(web::resource("/api/songs/{hash}/thumbnail").route(web::get().to(thumbnail)))
+            .service(web::resource("/api/songs").route(web::get().to(api_songs)))
+            .service(web::resource("/api/artists").route(web::get().to(api_artists)))
+            .service(web::resource("/api/queue").route(web::get().to(api_queue)))
+            .service(web::resource("/api/status").route(web::get().to(api_status)))
+            .service(web::resource("/api/history").route(web::get().to(api_history)))
+            .service(web::resource("/api/history.csv").route(web::get().to(api_history_csv)))
+            .service(web::resource("/api/top").route(web::get().to(api_top)))
+            .service(web::resource("/api/requests").route(web::get().to(api_requests)))
+            .service(
+                web::resource("/api/requests/{id}/approve")
+                    .wrap(AdminOnly)
+                    .route(web::post().to(approve_request)),
+            )
+            .service(
+                web::resource("/api/requests/{id}/reject")
+                    .wrap(AdminOnly)
+                    .route(web::post().to(reject_request)),
+            )
+            .service(
+                web::resource("/api/requests/{id}/edit")
+                    .wrap(AdminOnly)
+                    .route(web::post().to(edit_request)),
+            )
+            .service(web::resource("/api/events").route(web::get().to(event_stream)))
+            .service(web::resource("/api/role").route(web::get().to(api_role)))
+            .service(web::resource("/api/login").route(web::post().to(login)))
+            .service(web::resource("/api/logout").route(web::post().to(logout)))
+            .service(web::resource("/api/guest").route(web::post().to(set_guest)))
+            .service(web::resource("/api/my").route(web::get().to(api_my_songs)))
+            .service(web::resource("/api/cancel/{id}").route(web::post().to(cancel)))
             .service(web::resource("/api/add").route(web::post().to(add)))
-            .service(web::resource("/api/playnow").route(web::post().to(playnow)))
-            .service(web::resource("/api/next").route(web::post().to(next)))
-            .service(web::resource("/api/clear").route(web::post().to(clear)))
-            .service(web::resource("/api/stop").route(web::post().to(stop)))
+            .service(
+                web::resource("/api/playnow")
+                    .wrap(AdminOnly)
+                    .route(web::post().to(playnow)),
+            )
+            .service(
+                web::resource("/api/next")
+                    .wrap(AdminOnly)
+                    .route(web::post().to(next)),
+            )
+            .service(
+                web::resource("/api/clear")
+                    .wrap(AdminOnly)
+                    .route(web::post().to(clear)),
+            )
+            .service(
+                web::resource("/api/stop")
+                    .wrap(AdminOnly)
+                    .route(web::post().to(stop)),
+            )
+            .service(
+                web::resource("/api/reorder")
+                    .wrap(AdminOnly)
+                    .route(web::post().to(move_queue)),
+            )
+            .service(
+                web::resource("/api/pause")
+                    .wrap(AdminOnly)
+                    .route(web::post().to(pause)),
+            )
+            .service(
+                web::resource("/api/volume")
+                    .wrap(AdminOnly)
+                    .route(web::post().to(volume)),
+            )
+            .service(
+                web::resource("/api/key")
+                    .wrap(AdminOnly)
+                    .route(web::post().to(key)),
+            )
+            .service(
+                web::resource("/api/refresh")
+                    .wrap(AdminOnly)
+                    .route(web::post().to(refresh)),
+            )
+            .service(web::resource("/static/vendor/{file}").route(web::get().to(vendor)))
             .service(actix_files::Files::new("/static", static_path))
             .default_service(
                 // 404 for GET request
@@ -197,6 +1216,494 @@ pub fn run() -> std::io::Result<()> {
     .bind(addr)?;
 
     println!("Actix has launched from http://0.0.0.0:{}", port);
+    match get_lan_url() {
+        Some(url) => println!("Guests can join at {}", url),
+        None => println!("No network found, guests can't join yet"),
+    }
 
     server.run()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http, test};
+    use karaoke::{
+        channel::WorkerCommand, collection::Collection, config::Config, history::History,
+        stats::Stats,
+    };
+
+    #[test]
+    fn test_api_queue() {
+        let context = Context::new(
+            Config::default(),
+            Collection::default(),
+            History::new(),
+            Stats::new(),
+        );
+        context.queue.lock().unwrap().extend(vec![
+            QueueEntry::from(Kfile {
+                duration: Some(60_000),
+                ..Kfile::default()
+            }),
+            QueueEntry::default(),
+        ]);
+
+        let mut app = test::init_service(
+            App::new()
+                .data(context)
+                .service(web::resource("/api/queue").route(web::get().to(api_queue))),
+        );
+        let req = test::TestRequest::get().uri("/api/queue").to_request();
+        let queue: Queue = test::read_response_json(&mut app, req);
+
+        //Default changeover gap of 30 seconds after the first song
+        let waits: Vec<Option<u64>> = queue.queue.iter().map(|e| e.wait).collect();
+        assert_eq!(waits, vec![Some(0), Some(90)]);
+        assert!(!queue.status.is_playing());
+    }
+
+    #[test]
+    fn test_stale_song_not_found() {
+        let context = Context::new(
+            Config::default(),
+            Collection::default(),
+            History::new(),
+            Stats::new(),
+        );
+        let mut app = test::init_service(
+            App::new()
+                .data(context.clone())
+                .service(web::resource("/api/add").route(web::post().to(add)))
+                .service(web::resource("/api/playnow").route(web::post().to(playnow))),
+        );
+        for uri in &["/api/add", "/api/playnow"] {
+            let req = test::TestRequest::post()
+                .uri(uri)
+                .header("content-type", "application/x-www-form-urlencoded")
+                .set_payload("hash=1234")
+                .to_request();
+            let resp = test::call_service(&mut app, req);
+            assert_eq!(resp.status(), http::StatusCode::NOT_FOUND);
+        }
+        assert!(context.worker_channel.1.is_empty());
+    }
+
+    #[test]
+    fn test_add_rejects_repeat() {
+        let kfile = Kfile {
+            song: String::from("Ballad"),
+            ..Kfile::default()
+        };
+        let context = Context::new(
+            Config::default(),
+            Collection::new(vec![kfile.clone()]),
+            History::new(),
+            Stats::new(),
+        );
+        context
+            .queue
+            .lock()
+            .unwrap()
+            .push(QueueEntry::from(kfile.clone()));
+        karaoke::worker::run(&context);
+
+        let mut app = test::init_service(
+            App::new()
+                .data(context.clone())
+                .service(web::resource("/api/add").route(web::post().to(add))),
+        );
+        let req = test::TestRequest::post()
+            .uri("/api/add")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .set_payload(format!("hash={}", kfile.id()))
+            .to_request();
+        let resp = test::call_service(&mut app, req);
+        assert_eq!(resp.status(), http::StatusCode::CONFLICT);
+        assert_eq!(context.queue.lock().unwrap().len(), 1);
+
+        //KJ can add it anyway
+        let req = test::TestRequest::post()
+            .uri("/api/add")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .set_payload(format!("hash={}&force=true", kfile.id()))
+            .to_request();
+        let resp = test::call_service(&mut app, req);
+        assert!(resp.status().is_success());
+        assert_eq!(context.queue.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_admin_routes_need_login() {
+        let config = Config {
+            admin_password: String::from("1234"),
+            ..Config::default()
+        };
+        let context = Context::new(config, Collection::default(), History::new(), Stats::new());
+
+        let mut app = test::init_service(
+            App::new()
+                .data(context.clone())
+                .wrap(CookieSession::signed(&[0; 32]).secure(false))
+                .service(web::resource("/api/login").route(web::post().to(login)))
+                .service(
+                    web::resource("/api/stop")
+                        .wrap(AdminOnly)
+                        .route(web::post().to(stop)),
+                ),
+        );
+        let login_with = |password: &str| {
+            test::TestRequest::post()
+                .uri("/api/login")
+                .header("content-type", "application/x-www-form-urlencoded")
+                .set_payload(format!("password={}", password))
+                .to_request()
+        };
+
+        let req = test::TestRequest::post().uri("/api/stop").to_request();
+        let resp = test::call_service(&mut app, req);
+        assert_eq!(resp.status(), http::StatusCode::FORBIDDEN);
+        assert!(context.worker_channel.1.is_empty());
+
+        for wrong in &["0000", "12345", ""] {
+            let resp = test::call_service(&mut app, login_with(wrong));
+            assert_eq!(resp.status(), http::StatusCode::UNAUTHORIZED);
+        }
+
+        //Session cookie from logging in unlocks admin routes
+        let resp = test::call_service(&mut app, login_with("1234"));
+        assert!(resp.status().is_success());
+        let cookie = resp
+            .headers()
+            .get("set-cookie")
+            .and_then(|cookie| cookie.to_str().ok())
+            .and_then(|cookie| cookie.split(';').next())
+            .unwrap()
+            .to_string();
+
+        let req = test::TestRequest::post()
+            .uri("/api/stop")
+            .header("cookie", cookie)
+            .to_request();
+        let resp = test::call_service(&mut app, req);
+        assert!(resp.status().is_success());
+        assert_eq!(context.worker_channel.1.len(), 1);
+    }
+
+    #[test]
+    fn test_guest_songs() {
+        let kfile = Kfile {
+            song: String::from("Ballad"),
+            ..Kfile::default()
+        };
+        let config = Config {
+            admin_password: String::from("1234"),
+            ..Config::default()
+        };
+        let context = Context::new(
+            config,
+            Collection::new(vec![kfile.clone()]),
+            History::new(),
+            Stats::new(),
+        );
+        karaoke::worker::run(&context);
+
+        let mut app = test::init_service(
+            App::new()
+                .data(context.clone())
+                .wrap(CookieSession::signed(&[0; 32]).secure(false))
+                .service(web::resource("/api/guest").route(web::post().to(set_guest)))
+                .service(web::resource("/api/my").route(web::get().to(api_my_songs)))
+                .service(web::resource("/api/cancel/{id}").route(web::post().to(cancel)))
+                .service(web::resource("/api/add").route(web::post().to(add))),
+        );
+        let post = |uri: &str, payload: String, cookie: &str| {
+            test::TestRequest::post()
+                .uri(uri)
+                .header("content-type", "application/x-www-form-urlencoded")
+                .header("cookie", cookie)
+                .set_payload(payload)
+                .to_request()
+        };
+
+        let resp = test::call_service(&mut app, post("/api/guest", "name=Alice".into(), ""));
+        let cookie = resp
+            .headers()
+            .get("set-cookie")
+            .and_then(|cookie| cookie.to_str().ok())
+            .and_then(|cookie| cookie.split(';').next())
+            .unwrap()
+            .to_string();
+
+        //Sung under the session name when no singer is given
+        let add = post("/api/add", format!("hash={}", kfile.id()), &cookie);
+        assert!(test::call_service(&mut app, add).status().is_success());
+        let req = test::TestRequest::get()
+            .uri("/api/my")
+            .header("cookie", cookie.as_str())
+            .to_request();
+        let mine: MySongs = test::read_response_json(&mut app, req);
+        assert_eq!(mine.queue.len(), 1);
+        assert_eq!(mine.queue[0].position, 0);
+        assert_eq!(mine.queue[0].estimate.singer, Some(String::from("Alice")));
+
+        //Other guests can't cancel it
+        let id = mine.queue[0].estimate.id;
+        let uri = format!("/api/cancel/{}", id);
+        let resp = test::call_service(&mut app, post(&uri, String::new(), ""));
+        assert_eq!(resp.status(), http::StatusCode::NOT_FOUND);
+        let resp = test::call_service(&mut app, post(&uri, String::new(), &cookie));
+        assert!(resp.status().is_success());
+        assert!(context.queue.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_api_songs() {
+        let kfile = |artist: &str, song: &str| Kfile {
+            artist: artist.to_string(),
+            song: song.to_string(),
+            ..Kfile::default()
+        };
+        let context = Context::new(
+            Config::default(),
+            Collection::new(vec![
+                kfile("Queen", "Bohemian Rhapsody"),
+                kfile("ABBA", "Waterloo"),
+                kfile("Queen", "99 Red Balloons"),
+            ]),
+            History::new(),
+            Stats::new(),
+        );
+        let waterloo = kfile("ABBA", "Waterloo").id();
+        for skipped in &[false, true] {
+            let entry = HistoryEntry {
+                song_id: waterloo,
+                artist: String::from("ABBA"),
+                song: String::from("Waterloo"),
+                singer: None,
+                started: 1,
+                played: 1000,
+                skipped: *skipped,
+            };
+            context.stats.lock().unwrap().record_play(&entry).unwrap();
+        }
+
+        let mut app = test::init_service(
+            App::new()
+                .data(context)
+                .service(web::resource("/api/songs").route(web::get().to(api_songs))),
+        );
+        let mut songs = |uri: &str| -> Listing<SongRow> {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            test::read_response_json(&mut app, req)
+        };
+
+        let all = songs("/api/songs");
+        let names: Vec<&str> = all
+            .page
+            .items
+            .iter()
+            .map(|row| row.kfile.song.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            vec!["99 Red Balloons", "Bohemian Rhapsody", "Waterloo"]
+        );
+        assert!(all.letters[1].empty);
+
+        let numbers = songs("/api/songs?letter=%23");
+        assert_eq!(numbers.page.total, 1);
+        let searched = songs("/api/songs?q=queen+rhap");
+        assert_eq!(searched.page.items[0].kfile.song, "Bohemian Rhapsody");
+        assert_eq!(searched.page.total, 1);
+        assert_eq!(searched.sort, None);
+
+        //Most played first, the rest still by name
+        let played = songs("/api/songs?sort=plays");
+        let names: Vec<&str> = played
+            .page
+            .items
+            .iter()
+            .map(|row| row.kfile.song.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            vec!["Waterloo", "99 Red Balloons", "Bohemian Rhapsody"]
+        );
+        assert_eq!(played.sort, Some(String::from("plays")));
+        let played = songs("/api/songs?sort=plays&q=queen");
+        assert_eq!(played.page.items[0].kfile.song, "99 Red Balloons");
+        assert_eq!(played.page.total, 2);
+    }
+
+    #[test]
+    fn test_playback_adjustments() {
+        let context = Context::new(
+            Config::default(),
+            Collection::default(),
+            History::new(),
+            Stats::new(),
+        );
+        let mut app = test::init_service(
+            App::new()
+                .data(context.clone())
+                .service(web::resource("/api/pause").route(web::post().to(pause)))
+                .service(web::resource("/api/volume").route(web::post().to(volume)))
+                .service(web::resource("/api/key").route(web::post().to(key))),
+        );
+        let mut post = |uri: &str, payload: &str| {
+            let req = test::TestRequest::post()
+                .uri(uri)
+                .header("content-type", "application/x-www-form-urlencoded")
+                .set_payload(payload.to_string())
+                .to_request();
+            test::call_service(&mut app, req).status()
+        };
+
+        assert!(post("/api/pause", "paused=true").is_success());
+        assert!(post("/api/volume", "volume=40").is_success());
+        assert!(post("/api/key", "key=-2").is_success());
+        assert_eq!(
+            post("/api/volume", "volume=-1"),
+            http::StatusCode::BAD_REQUEST
+        );
+
+        let sent: Vec<WorkerCommand> = context.worker_channel.1.try_iter().collect();
+        assert_eq!(sent.len(), 3);
+        assert!(matches!(sent[0], WorkerCommand::Pause { paused: true }));
+        assert!(matches!(sent[1], WorkerCommand::SetVolume { volume: 40 }));
+        assert!(matches!(sent[2], WorkerCommand::SetKey { key: -2 }));
+
+        //Index page starts from the status the player last reported
+        let data_path = std::path::PathBuf::from("tests/test_data/playback");
+        karaoke::embed::unload_files(&data_path);
+        let tera = load_templates(&data_path);
+        let html = tera.render("index.html", &PlayerStatus::default()).unwrap();
+        assert!(html.contains("paused: false, key: 0, volume: 100"));
+        std::fs::remove_dir_all(&data_path).unwrap();
+    }
+
+    #[test]
+    fn test_songs_sort_links() {
+        let data_path = std::path::PathBuf::from("tests/test_data/sort");
+        karaoke::embed::unload_files(&data_path);
+        let tera = load_templates(&data_path);
+        let browse = |sort: Option<&str>| Browse {
+            page: None,
+            letter: Some(String::from("#")),
+            q: Some(String::from("rock & roll")),
+            sort: sort.map(String::from),
+        };
+        let listing = |sort| {
+            get_listing(
+                "/songs",
+                Vec::<SongRow>::new(),
+                &browse(sort),
+                |row| &row.kfile.song,
+                |_, _| true,
+                Some(|row: &SongRow| row.stats.plays()),
+            )
+        };
+
+        let html = tera.render("songs.html", &listing(None)).unwrap();
+        assert!(html.contains("?sort=plays&amp;letter=%23&amp;q=rock%20%26%20roll"));
+        let html = tera.render("songs.html", &listing(Some("plays"))).unwrap();
+        assert!(html.contains("?sort=name&amp;letter=%23&amp;q=rock%20%26%20roll"));
+        assert!(html.contains(r#"name="sort" value="plays""#));
+
+        std::fs::remove_dir_all(&data_path).unwrap();
+    }
+
+    #[test]
+    fn test_theme_overrides_templates() {
+        let data_path = std::path::PathBuf::from("tests/test_data/theme");
+        karaoke::embed::unload_files(&data_path);
+        std::fs::create_dir_all(data_path.join("theme")).unwrap();
+        std::fs::write(
+            data_path.join("theme/404.html"),
+            "{% extends \"base.html\" %}{% block content %}Lost{% endblock content %}",
+        )
+        .unwrap();
+
+        let tera = load_templates(&data_path);
+        let html = tera
+            .render("404.html", &HashMap::<String, u64>::new())
+            .unwrap();
+        assert!(html.contains("Lost"));
+
+        std::fs::remove_dir_all(&data_path).unwrap();
+    }
+
+    #[test]
+    fn test_api_history() {
+        let context = Context::new(
+            Config::default(),
+            Collection::default(),
+            History::new(),
+            Stats::new(),
+        );
+        for started in 1..=2 {
+            let entry = HistoryEntry {
+                song_id: 0,
+                artist: String::from("Artist"),
+                song: String::from("Song"),
+                singer: None,
+                started,
+                played: 1000,
+                skipped: false,
+            };
+            context.history.lock().unwrap().record(entry).unwrap();
+        }
+
+        let mut app = test::init_service(
+            App::new()
+                .data(context)
+                .service(web::resource("/api/history").route(web::get().to(api_history))),
+        );
+        let req = test::TestRequest::get().uri("/api/history").to_request();
+        let history: HistoryList = test::read_response_json(&mut app, req);
+
+        let started: Vec<u64> = history.history.iter().map(|e| e.started).collect();
+        assert_eq!(started, vec![2, 1]);
+    }
+
+    #[test]
+    fn test_vendor_falls_back_to_cdn() {
+        let config = Config {
+            data_path: std::path::PathBuf::from("tests/test_data/no_vendor"),
+            ..Config::default()
+        };
+        let context = Context::new(config, Collection::default(), History::new(), Stats::new());
+
+        let mut app = test::init_service(
+            App::new()
+                .data(context)
+                .service(web::resource("/static/vendor/{file}").route(web::get().to(vendor))),
+        );
+        let req = test::TestRequest::get()
+            .uri("/static/vendor/jquery.min.js")
+            .to_request();
+        let resp = test::call_service(&mut app, req);
+        assert_eq!(resp.status(), http::StatusCode::FOUND);
+        assert_eq!(
+            resp.headers().get(http::header::LOCATION).unwrap(),
+            "https://code.jquery.com/jquery-3.4.0.min.js"
+        );
+
+        //Only the known libraries are served
+        let req = test::TestRequest::get()
+            .uri("/static/vendor/other.js")
+            .to_request();
+        let resp = test::call_service(&mut app, req);
+        assert_eq!(resp.status(), http::StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_reachable() {
+        assert!(reachable(&IpAddr::from([192, 168, 1, 20])));
+        assert!(reachable(&IpAddr::from([10, 0, 0, 5])));
+        assert!(!reachable(&IpAddr::from([127, 0, 0, 1])));
+        assert!(!reachable(&IpAddr::from([0, 0, 0, 0])));
+        assert!(!reachable(&IpAddr::from([169, 254, 3, 4])));
+    }
+}
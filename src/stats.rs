@@ -0,0 +1,247 @@
+use karaoke::{
+    collection::{Collection, Kfile},
+    history::HistoryEntry,
+};
+use rustbreak::{deser::Yaml, FileDatabase};
+use serde_derive::{Deserialize, Serialize};
+use std::{collections::HashMap, path::Path};
+
+pub type StatsDB = FileDatabase<HashMap<u64, SongStats>, Yaml>;
+
+//Counters for a song, keyed by its collection key. Last played is a unix timestamp
+#[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone, Default)]
+pub struct SongStats {
+    pub queued: u64,
+    pub completed: u64,
+    pub skipped: u64,
+    pub last_played: Option<u64>,
+}
+
+impl SongStats {
+    pub fn plays(&self) -> u64 {
+        self.completed + self.skipped
+    }
+
+    fn record_play(&mut self, entry: &HistoryEntry) {
+        if entry.skipped {
+            self.skipped += 1;
+        } else {
+            self.completed += 1;
+        }
+        self.last_played = self.last_played.max(Some(entry.started));
+    }
+}
+
+//All time counters, saved to stats.yaml in the data dir alongside the
+//collection db. Songs removed from the collection keep their counters
+#[derive(Debug, Default)]
+pub struct Stats {
+    db: Option<StatsDB>,
+    songs: HashMap<u64, SongStats>,
+}
+
+impl Stats {
+    //Stats that are only kept in memory
+    pub fn new() -> Self {
+        Stats::default()
+    }
+
+    //If file doesn't exist, create default. Load db from file.
+    pub fn open(data_path: &Path) -> Result<Self, failure::Error> {
+        let db_path = data_path.join("stats.yaml");
+        let exists = db_path.exists();
+        let db = StatsDB::from_path(db_path, HashMap::new())?;
+        if !exists {
+            db.save()?;
+        }
+        db.load()?;
+
+        let songs = db.read(|songs| songs.clone())?;
+        Ok(Stats {
+            db: Some(db),
+            songs,
+        })
+    }
+
+    pub fn get(&self, id: u64) -> SongStats {
+        self.songs.get(&id).cloned().unwrap_or_default()
+    }
+
+    pub fn all(&self) -> &HashMap<u64, SongStats> {
+        &self.songs
+    }
+
+    pub fn record_queued(&mut self, id: u64) -> Result<(), failure::Error> {
+        self.songs.entry(id).or_default().queued += 1;
+        self.save(id)
+    }
+
+    pub fn record_play(&mut self, entry: &HistoryEntry) -> Result<(), failure::Error> {
+        self.songs
+            .entry(entry.song_id)
+            .or_default()
+            .record_play(entry);
+        self.save(entry.song_id)
+    }
+
+    fn save(&self, id: u64) -> Result<(), failure::Error> {
+        if let Some(db) = &self.db {
+            let stats = self.get(id);
+            db.write(|songs| {
+                songs.insert(id, stats);
+            })?;
+            db.save()?;
+        }
+        Ok(())
+    }
+}
+
+//Play counts from history entries started at or after since. Queued isn't
+//logged in history, so is left at 0
+pub fn window_stats(history: &[HistoryEntry], since: u64) -> HashMap<u64, SongStats> {
+    let mut songs: HashMap<u64, SongStats> = HashMap::new();
+    for entry in history.iter().filter(|entry| entry.started >= since) {
+        songs.entry(entry.song_id).or_default().record_play(entry);
+    }
+    songs
+}
+
+#[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone)]
+pub struct TopSong {
+    pub id: u64,
+    pub kfile: Kfile,
+    pub stats: SongStats,
+}
+
+#[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone)]
+pub struct TopArtist {
+    pub hash: u64,
+    pub name: String,
+    pub plays: u64,
+}
+
+//Most played songs still in the collection, ties broken by times queued then name
+pub fn top_songs(
+    collection: &Collection,
+    songs: &HashMap<u64, SongStats>,
+    limit: usize,
+) -> Vec<TopSong> {
+    let mut top: Vec<TopSong> = songs
+        .iter()
+        .filter(|(_, stats)| stats.plays() > 0 || stats.queued > 0)
+        .filter_map(|(id, stats)| {
+            collection.by_song.get(id).map(|kfile| TopSong {
+                id: *id,
+                kfile: kfile.clone(),
+                stats: stats.clone(),
+            })
+        })
+        .collect();
+    top.sort_by(|a, b| {
+        b.stats
+            .plays()
+            .cmp(&a.stats.plays())
+            .then(b.stats.queued.cmp(&a.stats.queued))
+            .then(a.kfile.song.cmp(&b.kfile.song))
+    });
+    top.truncate(limit);
+    top
+}
+
+//Most played artists, summed over their songs still in the collection
+pub fn top_artists(
+    collection: &Collection,
+    songs: &HashMap<u64, SongStats>,
+    limit: usize,
+) -> Vec<TopArtist> {
+    let mut plays: HashMap<u64, u64> = HashMap::new();
+    for (id, stats) in songs.iter() {
+        if let Some(kfile) = collection.by_song.get(id) {
+            *plays.entry(kfile.artist_hash).or_default() += stats.plays();
+        }
+    }
+
+    let mut top: Vec<TopArtist> = plays
+        .into_iter()
+        .filter(|(_, plays)| *plays > 0)
+        .filter_map(|(hash, plays)| {
+            collection.by_artist.get(&hash).map(|artist| TopArtist {
+                hash,
+                name: artist.name.clone(),
+                plays,
+            })
+        })
+        .collect();
+    top.sort_by(|a, b| b.plays.cmp(&a.plays).then(a.name.cmp(&b.name)));
+    top.truncate(limit);
+    top
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use karaoke::collection::calculate_hash;
+
+    //Artist hash is set like a scanned song's, so songs group under their artist
+    fn kfile(artist: &str, song: &str) -> Kfile {
+        Kfile {
+            artist: artist.to_string(),
+            artist_hash: calculate_hash(&artist.to_string()),
+            song: song.to_string(),
+            ..Kfile::default()
+        }
+    }
+
+    fn played(kfile: &Kfile, started: u64, skipped: bool) -> HistoryEntry {
+        HistoryEntry {
+            song_id: kfile.id(),
+            artist: kfile.artist.clone(),
+            song: kfile.song.clone(),
+            singer: None,
+            started,
+            played: 0,
+            skipped,
+        }
+    }
+
+    #[test]
+    fn test_window_stats_and_top() {
+        let first = kfile("Artist 1", "Song 1");
+        let second = kfile("Artist 2", "Song 2");
+        let collection = Collection::new(vec![first.clone(), second.clone()]);
+        let history = vec![
+            played(&first, 100, false),
+            played(&second, 200, false),
+            played(&second, 300, true),
+            played(&first, 400, true),
+            played(&first, 500, false),
+        ];
+
+        let all = window_stats(&history, 0);
+        assert_eq!(
+            all[&first.id()],
+            SongStats {
+                queued: 0,
+                completed: 2,
+                skipped: 1,
+                last_played: Some(500),
+            }
+        );
+        let top = top_songs(&collection, &all, 10);
+        assert_eq!(top[0].kfile, first);
+        assert_eq!(top.len(), 2);
+
+        //Only the last two plays of each song fall in the window
+        let recent = window_stats(&history, 200);
+        let top = top_artists(&collection, &recent, 1);
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].name, "Artist 1");
+        assert_eq!(top[0].plays, 2);
+
+        //Ties go to the song queued more often
+        let mut recent = window_stats(&history, 200);
+        recent.get_mut(&second.id()).unwrap().queued = 1;
+        let top = top_songs(&collection, &recent, 10);
+        assert_eq!(top[0].kfile, second);
+    }
+}
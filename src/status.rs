@@ -0,0 +1,103 @@
+use karaoke::{collection::Kfile, queue::QueueEntry};
+use serde_derive::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+//What the player is doing, updated by the player thread and read by the web
+//layer. Position & duration are in milliseconds, key is a semitone offset and
+//volume a percentage. Started is the unix timestamp the song began at
+#[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone)]
+pub struct PlayerStatus {
+    pub kfile: Option<Kfile>,
+    pub singer: Option<String>,
+    pub started: Option<u64>,
+    pub position: u64,
+    pub duration: Option<u64>,
+    pub paused: bool,
+    pub key: i8,
+    pub volume: u8,
+}
+
+impl Default for PlayerStatus {
+    fn default() -> Self {
+        PlayerStatus {
+            kfile: None,
+            singer: None,
+            started: None,
+            position: 0,
+            duration: None,
+            paused: false,
+            key: 0,
+            volume: 100,
+        }
+    }
+}
+
+impl PlayerStatus {
+    pub fn is_playing(&self) -> bool {
+        self.kfile.is_some()
+    }
+
+    //Song has started, key & volume carry over between songs
+    pub fn start(&mut self, entry: QueueEntry) {
+        self.duration = entry.kfile.expected_duration();
+        self.kfile = Some(entry.kfile);
+        self.singer = entry.singer;
+        self.started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .ok();
+        self.position = 0;
+        self.paused = false;
+    }
+
+    pub fn stop(&mut self) {
+        self.kfile = None;
+        self.singer = None;
+        self.started = None;
+        self.position = 0;
+        self.duration = None;
+        self.paused = false;
+    }
+
+    //Milliseconds left of the current song, None if nothing is playing or its
+    //duration is unknown
+    pub fn remaining(&self) -> Option<u64> {
+        self.kfile
+            .as_ref()
+            .and(self.duration)
+            .map(|duration| duration.saturating_sub(self.position))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_start_stop() {
+        let mut status = PlayerStatus {
+            volume: 50,
+            ..PlayerStatus::default()
+        };
+        assert!(!status.is_playing());
+        assert_eq!(status.remaining(), None);
+
+        status.start(QueueEntry::new(
+            Kfile {
+                duration: Some(200_000),
+                ..Kfile::default()
+            },
+            Some(String::from("Alice")),
+        ));
+        status.position = 80_000;
+        assert!(status.is_playing());
+        assert!(status.started.is_some());
+        assert_eq!(status.remaining(), Some(120_000));
+
+        status.stop();
+        assert!(!status.is_playing());
+        assert_eq!(status.duration, None);
+        assert_eq!(status.singer, None);
+        assert_eq!(status.volume, 50);
+    }
+}
@@ -0,0 +1,514 @@
+use failure::bail;
+use image::{Rgba, RgbaImage};
+use log::error;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    f32::consts,
+    fmt,
+    fs::read_dir,
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+//Size video loop frames are decoded at, padded with the letterbox color to keep
+//their aspect ratio. Outputs scale them up to the screen
+const VIDEO_WIDTH: u32 = 640;
+const VIDEO_HEIGHT: u32 = 360;
+
+//Steps in a gradient backdrop, stretched to the screen
+const GRADIENT_STEPS: u32 = 256;
+
+//Cdg border area, never drawn to by most discs
+const BORDER_X: u32 = 6;
+const BORDER_Y: u32 = 12;
+
+pub type Color = (f32, f32, f32, f32);
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackgroundMode {
+    Rainbow,
+    Solid,
+    Gradient,
+    Image,
+    Video,
+}
+
+//How the cdg is sized to the screen. Fit is as large as fits keeping its shape,
+//integer the same but only whole multiples for crisp pixels, fill stretches to
+//the whole screen, custom uses cdg_scale
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CdgScaling {
+    Fit,
+    Integer,
+    Fill,
+    Custom,
+}
+
+//Nearest keeps blocky pixels, linear smooths them
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CdgFilter {
+    Nearest,
+    Linear,
+}
+
+//Left, bottom, width & height of a rectangle, from the bottom left like OpenGL
+pub type Rect = (u32, u32, u32, u32);
+
+//How the player screen looks. Colors are "#rrggbb" hex, paths left unset use
+//the built in idle image & rainbow background
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PlayerTheme {
+    //Image shown while idle, or a folder of images cycled every slideshow_interval seconds
+    pub idle_image: Option<PathBuf>,
+    pub idle_slideshow: Option<PathBuf>,
+    pub slideshow_interval: u64,
+    //Drawn behind the cdg while a song plays. Gradient runs from
+    //background_color at the top to gradient_color at the bottom
+    pub background: BackgroundMode,
+    pub background_color: String,
+    pub gradient_color: String,
+    pub background_image: Option<PathBuf>,
+    pub background_video: Option<PathBuf>,
+    pub cdg_scaling: CdgScaling,
+    pub cdg_scale: f32,
+    //Leaves out the cdg's border area, 6 pixels each side and 12 top & bottom
+    pub cdg_border_crop: bool,
+    pub cdg_filter: CdgFilter,
+    //Fills the screen around images that don't match its aspect ratio
+    pub letterbox_color: String,
+}
+
+impl Default for PlayerTheme {
+    fn default() -> Self {
+        PlayerTheme {
+            idle_image: None,
+            idle_slideshow: None,
+            slideshow_interval: 10,
+            background: BackgroundMode::Rainbow,
+            background_color: String::from("#000000"),
+            gradient_color: String::from("#202060"),
+            background_image: None,
+            background_video: None,
+            cdg_scaling: CdgScaling::Fit,
+            cdg_scale: 1.5,
+            cdg_border_crop: false,
+            cdg_filter: CdgFilter::Linear,
+            letterbox_color: String::from("#000000"),
+        }
+    }
+}
+
+impl PlayerTheme {
+    //Bad colors are logged and drawn black
+    pub fn letterbox(&self) -> Color {
+        parse_color(&self.letterbox_color).unwrap_or_else(|e| {
+            error!("Invalid letterbox_color: {}", e);
+            (0.0, 0.0, 0.0, 1.0)
+        })
+    }
+
+    //Part of the cdg image shown
+    pub fn cdg_source(&self, cdg: (u32, u32)) -> Rect {
+        if self.cdg_border_crop && cdg.0 > BORDER_X * 2 && cdg.1 > BORDER_Y * 2 {
+            (
+                BORDER_X,
+                BORDER_Y,
+                cdg.0 - BORDER_X * 2,
+                cdg.1 - BORDER_Y * 2,
+            )
+        } else {
+            (0, 0, cdg.0, cdg.1)
+        }
+    }
+
+    //Where the shown part of the cdg is drawn, centered on the screen. Parts
+    //scaled past the screen's edges are cut off
+    pub fn cdg_target(&self, cdg: (u32, u32), screen: (u32, u32)) -> Rect {
+        let (_, _, width, height) = self.cdg_source(cdg);
+        let fit = (screen.0 as f32 / width as f32).min(screen.1 as f32 / height as f32);
+        let (scale_x, scale_y) = match self.cdg_scaling {
+            CdgScaling::Fit => (fit, fit),
+            CdgScaling::Integer => (fit.floor().max(1.0), fit.floor().max(1.0)),
+            CdgScaling::Fill => (
+                screen.0 as f32 / width as f32,
+                screen.1 as f32 / height as f32,
+            ),
+            CdgScaling::Custom => (self.cdg_scale, self.cdg_scale),
+        };
+        let width = (width as f32 * scale_x).round() as u32;
+        let height = (height as f32 * scale_y).round() as u32;
+        (
+            screen.0.saturating_sub(width) / 2,
+            screen.1.saturating_sub(height) / 2,
+            width,
+            height,
+        )
+    }
+}
+
+//Parses "#rrggbb" into an opaque color
+pub fn parse_color(hex: &str) -> Result<Color, failure::Error> {
+    let digits = hex.trim().trim_start_matches('#');
+    if digits.len() != 6 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("Expected a color like #1a2b3c, got {:?}", hex);
+    }
+    let channel = |i: usize| u8::from_str_radix(&digits[i..i + 2], 16).unwrap() as f32 / 255.0;
+    Ok((channel(0), channel(2), channel(4), 1.0))
+}
+
+fn to_rgba(color: Color) -> Rgba<u8> {
+    Rgba {
+        data: [
+            (color.0 * 255.0).round() as u8,
+            (color.1 * 255.0).round() as u8,
+            (color.2 * 255.0).round() as u8,
+            (color.3 * 255.0).round() as u8,
+        ],
+    }
+}
+
+//What's drawn behind the cdg for one frame. Frame counts up whenever the image
+//changes, so outputs can keep an unchanged image uploaded. Stretched images
+//fill the screen, others keep their aspect ratio inside the letterbox
+pub enum Backdrop<'a> {
+    Color(Color),
+    Image {
+        image: &'a RgbaImage,
+        frame: u64,
+        stretch: bool,
+    },
+}
+
+//Images don't implement Debug, only their size is shown
+impl fmt::Debug for Backdrop<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Backdrop::Color(color) => f.debug_tuple("Color").field(color).finish(),
+            Backdrop::Image {
+                image,
+                frame,
+                stretch,
+            } => f
+                .debug_struct("Image")
+                .field("dimensions", &image.dimensions())
+                .field("frame", frame)
+                .field("stretch", stretch)
+                .finish(),
+        }
+    }
+}
+
+//Produces a backdrop for each frame of a song
+pub enum BackdropSource {
+    Rainbow { i: f32 },
+    Color(Color),
+    Image { image: RgbaImage, stretch: bool },
+    Video(VideoLoop),
+}
+
+impl BackdropSource {
+    pub fn new(theme: &PlayerTheme) -> Result<Self, failure::Error> {
+        let source = match theme.background {
+            BackgroundMode::Rainbow => BackdropSource::Rainbow { i: 0.0 },
+            BackgroundMode::Solid => BackdropSource::Color(parse_color(&theme.background_color)?),
+            BackgroundMode::Gradient => BackdropSource::Image {
+                image: gradient(
+                    parse_color(&theme.background_color)?,
+                    parse_color(&theme.gradient_color)?,
+                ),
+                stretch: true,
+            },
+            BackgroundMode::Image => match &theme.background_image {
+                Some(path) => BackdropSource::Image {
+                    image: image::open(path)?.to_rgba(),
+                    stretch: false,
+                },
+                None => bail!("background_image must be set for an image background"),
+            },
+            BackgroundMode::Video => match &theme.background_video {
+                Some(path) => {
+                    BackdropSource::Video(VideoLoop::start(path, &theme.letterbox_color)?)
+                }
+                None => bail!("background_video must be set for a video background"),
+            },
+        };
+        Ok(source)
+    }
+
+    pub fn frame(&mut self) -> Backdrop<'_> {
+        match self {
+            BackdropSource::Rainbow { i } => Backdrop::Color(rainbow_cycle(i, 4096.0)),
+            BackdropSource::Color(color) => Backdrop::Color(*color),
+            BackdropSource::Image { image, stretch } => Backdrop::Image {
+                image,
+                frame: 0,
+                stretch: *stretch,
+            },
+            BackdropSource::Video(video) => video.frame(),
+        }
+    }
+}
+
+//Sine wave formula for rainbow cycling background color
+fn rainbow_cycle(i: &mut f32, size: f32) -> Color {
+    *i = if (*i + 1.0) % size == 0.0 {
+        0.0
+    } else {
+        *i + 1.0
+    };
+    let red =
+        ((consts::PI / size * 2.0 * *i + 0.0 * consts::PI / 3.0).sin() * 127.0).floor() + 128.0;
+    let green =
+        ((consts::PI / size * 2.0 * *i + 4.0 * consts::PI / 3.0).sin() * 127.0).floor() + 128.0;
+    let blue =
+        ((consts::PI / size * 2.0 * *i + 8.0 * consts::PI / 3.0).sin() * 127.0).floor() + 128.0;
+
+    (red / 255.0, green / 255.0, blue / 255.0, 1.0)
+}
+
+//Column fading from top to bottom color
+fn gradient(top: Color, bottom: Color) -> RgbaImage {
+    let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+    RgbaImage::from_fn(1, GRADIENT_STEPS, |_, y| {
+        let t = y as f32 / (GRADIENT_STEPS - 1) as f32;
+        to_rgba((
+            lerp(top.0, bottom.0, t),
+            lerp(top.1, bottom.1, t),
+            lerp(top.2, bottom.2, t),
+            1.0,
+        ))
+    })
+}
+
+//Video decoded by ffmpeg on repeat, at its own frame rate. Frames are read on
+//a separate thread, the latest one is shown each time the player draws
+pub struct VideoLoop {
+    ffmpeg: Child,
+    latest: Arc<Mutex<(u64, RgbaImage)>>,
+    current: RgbaImage,
+    frame: u64,
+}
+
+impl VideoLoop {
+    pub fn start(path: &Path, letterbox: &str) -> Result<Self, failure::Error> {
+        let filter = format!(
+            "scale={w}:{h}:force_original_aspect_ratio=decrease,pad={w}:{h}:(ow-iw)/2:(oh-ih)/2:color={c}",
+            w = VIDEO_WIDTH,
+            h = VIDEO_HEIGHT,
+            c = letterbox
+        );
+        let mut ffmpeg = Command::new("ffmpeg")
+            .args(["-loglevel", "error", "-re", "-stream_loop", "-1", "-i"])
+            .arg(path)
+            .args([
+                "-an", "-vf", &filter, "-f", "rawvideo", "-pix_fmt", "rgba", "-",
+            ])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        let blank = RgbaImage::new(VIDEO_WIDTH, VIDEO_HEIGHT);
+        let latest = Arc::from(Mutex::from((0, blank.clone())));
+        let mut stdout = ffmpeg.stdout.take().unwrap();
+        let frames = latest.clone();
+        thread::spawn(move || {
+            let mut buffer = vec![0u8; (VIDEO_WIDTH * VIDEO_HEIGHT * 4) as usize];
+            while stdout.read_exact(&mut buffer).is_ok() {
+                let image = RgbaImage::from_raw(VIDEO_WIDTH, VIDEO_HEIGHT, buffer.clone()).unwrap();
+                let mut frames = frames.lock().unwrap();
+                *frames = (frames.0 + 1, image);
+            }
+        });
+
+        Ok(VideoLoop {
+            ffmpeg,
+            latest,
+            current: blank,
+            frame: 0,
+        })
+    }
+
+    fn frame(&mut self) -> Backdrop<'_> {
+        let latest = self.latest.lock().unwrap();
+        if latest.0 != self.frame {
+            self.frame = latest.0;
+            self.current = latest.1.clone();
+        }
+        drop(latest);
+        Backdrop::Image {
+            image: &self.current,
+            frame: self.frame,
+            stretch: false,
+        }
+    }
+}
+
+//Stops decoding once the song is over
+impl Drop for VideoLoop {
+    fn drop(&mut self) {
+        let _ = self.ffmpeg.kill();
+        let _ = self.ffmpeg.wait();
+    }
+}
+
+//Images in a folder shown in name order, moving on every interval
+#[derive(Debug)]
+pub struct Slideshow {
+    images: Vec<PathBuf>,
+    interval: Duration,
+    index: usize,
+    shown: Instant,
+}
+
+impl Slideshow {
+    pub fn open(dir: &Path, interval: Duration) -> Result<Self, failure::Error> {
+        let mut images: Vec<PathBuf> = read_dir(dir)?
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| {
+                let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+                ["png", "jpg", "jpeg", "bmp", "gif"].contains(&extension.to_lowercase().as_str())
+            })
+            .collect();
+        if images.is_empty() {
+            bail!("No images found in slideshow folder {:?}", dir);
+        }
+        images.sort();
+        Ok(Slideshow {
+            images,
+            interval,
+            index: 0,
+            shown: Instant::now(),
+        })
+    }
+
+    pub fn current(&self) -> &Path {
+        &self.images[self.index]
+    }
+
+    //Next slide should be shown
+    pub fn due(&self) -> bool {
+        self.images.len() > 1 && self.shown.elapsed() >= self.interval
+    }
+
+    //When the next slide should be shown, None for a single image
+    pub fn next_due(&self) -> Option<Instant> {
+        if self.images.len() > 1 {
+            Some(self.shown + self.interval)
+        } else {
+            None
+        }
+    }
+
+    pub fn advance(&mut self) {
+        self.index = (self.index + 1) % self.images.len();
+        self.shown = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    #[test]
+    fn test_backdrops() {
+        assert_eq!(parse_color("#ff0000").unwrap(), (1.0, 0.0, 0.0, 1.0));
+        assert!(parse_color("red").is_err());
+
+        let theme = PlayerTheme {
+            background: BackgroundMode::Solid,
+            background_color: String::from("#102030"),
+            ..PlayerTheme::default()
+        };
+        match BackdropSource::new(&theme).unwrap().frame() {
+            Backdrop::Color(color) => assert_eq!(to_rgba(color).data, [16, 32, 48, 255]),
+            backdrop => panic!("Expected solid color, got {:?}", backdrop),
+        }
+
+        let theme = PlayerTheme {
+            background: BackgroundMode::Gradient,
+            background_color: String::from("#000000"),
+            gradient_color: String::from("#ffffff"),
+            ..PlayerTheme::default()
+        };
+        let mut source = BackdropSource::new(&theme).unwrap();
+        match source.frame() {
+            Backdrop::Image { image, stretch, .. } => {
+                assert!(stretch);
+                assert_eq!(image.get_pixel(0, 0).data, [0, 0, 0, 255]);
+                assert_eq!(
+                    image.get_pixel(0, GRADIENT_STEPS - 1).data,
+                    [255, 255, 255, 255]
+                );
+            }
+            backdrop => panic!("Expected gradient image, got {:?}", backdrop),
+        }
+
+        //Image backgrounds need an image
+        let theme = PlayerTheme {
+            background: BackgroundMode::Image,
+            ..PlayerTheme::default()
+        };
+        assert!(BackdropSource::new(&theme).is_err());
+    }
+
+    #[test]
+    fn test_cdg_scaling() {
+        let cdg = (300, 216);
+        let screen = (1366, 768);
+        let theme = |cdg_scaling, cdg_border_crop| PlayerTheme {
+            cdg_scaling,
+            cdg_border_crop,
+            ..PlayerTheme::default()
+        };
+
+        assert_eq!(
+            theme(CdgScaling::Fit, false).cdg_target(cdg, screen),
+            (149, 0, 1067, 768)
+        );
+        assert_eq!(
+            theme(CdgScaling::Integer, false).cdg_target(cdg, screen),
+            (233, 60, 900, 648)
+        );
+        assert_eq!(
+            theme(CdgScaling::Fill, false).cdg_target(cdg, screen),
+            (0, 0, 1366, 768)
+        );
+        assert_eq!(
+            theme(CdgScaling::Custom, false).cdg_target(cdg, screen),
+            (458, 222, 450, 324)
+        );
+
+        //Cropped to 288x192, which fits at 4x
+        let cropped = theme(CdgScaling::Fit, true);
+        assert_eq!(cropped.cdg_source(cdg), (6, 12, 288, 192));
+        assert_eq!(cropped.cdg_target(cdg, screen), (107, 0, 1152, 768));
+    }
+
+    #[test]
+    fn test_slideshow() {
+        let dir = PathBuf::from("tests/test_data/slideshow");
+        create_dir_all(&dir).unwrap();
+        for name in &["b.png", "a.jpg", "notes.txt"] {
+            write(dir.join(name), "").unwrap();
+        }
+
+        let mut slideshow = Slideshow::open(&dir, Duration::from_secs(0)).unwrap();
+        assert_eq!(slideshow.current(), dir.join("a.jpg").as_path());
+        assert!(slideshow.due());
+        assert!(slideshow.next_due().unwrap() <= Instant::now());
+        slideshow.advance();
+        slideshow.advance();
+        assert_eq!(slideshow.current(), dir.join("a.jpg").as_path());
+
+        remove_dir_all(&dir).unwrap();
+    }
+}
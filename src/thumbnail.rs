@@ -0,0 +1,165 @@
+use image::{GenericImage, RgbaImage};
+use karaoke::collection::{Collection, Kfile};
+use rayon::prelude::*;
+use std::{
+    fs::{read_dir, remove_file, write, DirBuilder, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+//Only search the first minute of each song for a title card
+const MAX_SECTORS: usize = 75 * 60;
+
+pub fn thumbnail_dir(data_path: &Path) -> PathBuf {
+    let mut path = data_path.to_path_buf();
+    path.push("thumbnails");
+    path
+}
+
+pub fn thumbnail_path(data_path: &Path, id: u64) -> PathBuf {
+    let mut path = thumbnail_dir(data_path);
+    path.push(format!("{}.png", id));
+    path
+}
+
+//Marker left for songs without a non-blank frame, so they aren't scanned again
+pub fn no_thumbnail_path(data_path: &Path, id: u64) -> PathBuf {
+    thumbnail_path(data_path, id).with_extension("none")
+}
+
+//Creates missing thumbnails for every song in collection, and removes
+//thumbnails of songs no longer in it
+pub fn refresh(collection: &Collection, data_path: &Path) -> Result<(), failure::Error> {
+    let dir = thumbnail_dir(data_path);
+    DirBuilder::new().recursive(true).create(&dir)?;
+
+    for entry in read_dir(&dir)?.filter_map(Result::ok) {
+        let path = entry.path();
+        let id = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse::<u64>().ok());
+        match id {
+            Some(id) if collection.by_song.contains_key(&id) => {}
+            _ => remove_file(&path)?,
+        }
+    }
+
+    let created = collection
+        .by_song
+        .par_iter()
+        .filter(|(id, _)| {
+            !thumbnail_path(data_path, **id).exists()
+                && !no_thumbnail_path(data_path, **id).exists()
+        })
+        .filter_map(|(id, kfile)| create_thumbnail(kfile, &thumbnail_path(data_path, *id)).ok())
+        .filter(|created| *created)
+        .count();
+    println!("Thumbnails created: {}", created);
+
+    Ok(())
+}
+
+//Saves first non-blank frame of kfile as a png to path, returns false if the
+//song has no such frame. That's remembered with an empty marker next to path
+pub fn create_thumbnail(kfile: &Kfile, path: &Path) -> Result<bool, failure::Error> {
+    let image = first_frame(&kfile.cdg_path)?;
+    if let Some(dir) = path.parent() {
+        DirBuilder::new().recursive(true).create(dir)?;
+    }
+    match image {
+        Some(image) => {
+            image.save(path)?;
+            Ok(true)
+        }
+        None => {
+            write(path.with_extension("none"), "")?;
+            Ok(false)
+        }
+    }
+}
+
+//Runs cdg sectors through the interpreter until something other than a
+//solid color is on screen
+fn first_frame(cdg_path: &Path) -> Result<Option<RgbaImage>, failure::Error> {
+    let cdg = File::open(cdg_path)?;
+    let mut scsi = cdg::SubchannelStreamIter::new(BufReader::new(cdg));
+    let mut cdg_interp = cdg_renderer::CdgInterpreter::new();
+    let mut cdg_image = RgbaImage::new(300, 216);
+
+    for _ in 0..MAX_SECTORS {
+        match scsi.next() {
+            Some(sector) => {
+                for cmd in sector {
+                    cdg_interp.handle_cmd(cmd);
+                }
+            }
+            None => break,
+        }
+
+        //Only inspect the frame when a command has changed it
+        if cdg_interp.dirty().is_none() {
+            continue;
+        }
+        cdg_interp.clear_dirty_region();
+
+        cdg_image.copy_from(&cdg_interp, 0, 0);
+        let first = *cdg_image.get_pixel(0, 0);
+        if cdg_image.pixels().any(|p| *p != first) {
+            return Ok(Some(cdg_image));
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Sector with a single cdg packet, cmd & data placed at the start of the packet
+    fn sector(cmd: u8, data: &[u8]) -> Vec<u8> {
+        let mut sector = vec![0u8; 96];
+        sector[0] = 9;
+        sector[1] = cmd;
+        sector[4..4 + data.len()].copy_from_slice(data);
+        sector
+    }
+
+    #[test]
+    fn test_first_frame() {
+        let cdg_path = PathBuf::from("tests/test_data/thumbnail.cdg");
+        let png_path = PathBuf::from("tests/test_data/thumbnail.png");
+
+        //Palette with color 15 set to white, then a memory preset only, so the
+        //screen stays a solid color
+        let mut clut = vec![0u8; 16];
+        clut[14] = 0x3F;
+        clut[15] = 0x3F;
+        let mut data = sector(31, &clut);
+        data.extend(sector(1, &[2]));
+        data.extend(vec![0u8; 96 * 10]);
+        write(&cdg_path, &data).unwrap();
+        assert!(first_frame(&cdg_path).unwrap().is_none());
+
+        //Which is remembered, instead of a thumbnail
+        let kfile = Kfile {
+            cdg_path: cdg_path.clone(),
+            ..Kfile::default()
+        };
+        assert!(!create_thumbnail(&kfile, &png_path).unwrap());
+        assert!(png_path.with_extension("none").exists());
+        remove_file(png_path.with_extension("none")).unwrap();
+
+        //Tile with color 15 foreground, drawn at tile column 5, row 3
+        let mut tile = vec![0, 15, 3, 5];
+        tile.extend(vec![0x3F; 12]);
+        data.extend(sector(6, &tile));
+        write(&cdg_path, &data).unwrap();
+        let image = first_frame(&cdg_path).unwrap().unwrap();
+        assert_eq!(image.get_pixel(5 * 6, 3 * 12).data, [255, 255, 255, 255]);
+        assert_ne!(image.get_pixel(0, 0).data, [255, 255, 255, 255]);
+
+        remove_file(&cdg_path).unwrap();
+    }
+}
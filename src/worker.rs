@@ -1,25 +1,30 @@
-use crossbeam_channel::{select, Receiver, Sender};
+use crossbeam_channel::{Receiver, Sender};
 use karaoke::{
-    channel::{
-        LiveCommand, PlayerCommand, WorkerCommand, LIVE_CHANNEL, PLAYER_CHANNEL, WORKER_CHANNEL,
-    },
-    collection::Kfile,
-    queue::PLAY_QUEUE,
+    channel::{PlayerCommand, WorkerCommand},
+    config::Config,
+    context::Context,
+    events::{Broadcaster, Event},
+    history::History,
+    queue::{find_repeat, QueueEntry, QueueError},
+    requests::{Requested, Requests},
+    stats::Stats,
+    status::PlayerStatus,
 };
+use log::error;
 use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
     sync::{Arc, Mutex},
-    thread, time,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-pub fn run() {
+//Handles commands in the order they're sent, blocking until each arrives
+pub fn run(context: &Context) {
+    let worker = Worker::new(context);
     thread::spawn(move || {
-        let worker = Worker::new();
-        loop {
-            select! {
-                recv(worker.worker_receiver) -> cmd => worker.process_cmd(cmd.unwrap()),
-                default() => {},
-            }
-            thread::sleep(time::Duration::from_millis(50));
+        for cmd in worker.worker_receiver.iter() {
+            worker.process_cmd(cmd);
         }
     });
 }
@@ -28,21 +33,33 @@ pub fn run() {
 struct Worker {
     worker_receiver: Receiver<WorkerCommand>,
     player_sender: Sender<PlayerCommand>,
-    live_sender: Sender<LiveCommand>,
-    queue: Arc<Mutex<Vec<Kfile>>>,
+    queue: Arc<Mutex<Vec<QueueEntry>>>,
+    status: Arc<Mutex<PlayerStatus>>,
+    events: Arc<Mutex<Broadcaster>>,
+    stats: Arc<Mutex<Stats>>,
+    history: Arc<Mutex<History>>,
+    requests: Arc<Mutex<Requests>>,
+    config: Arc<Config>,
+    //When each requester last had a song queued, for the request cooldown
+    last_request: RefCell<HashMap<String, Instant>>,
+    //Id given to the last queue entry
+    last_id: Cell<u64>,
 }
 
 impl Worker {
-    fn new() -> Self {
-        let worker_receiver = WORKER_CHANNEL.1.clone();
-        let player_sender = PLAYER_CHANNEL.0.clone();
-        let live_sender = LIVE_CHANNEL.0.clone();
-        let queue = PLAY_QUEUE.clone();
+    fn new(context: &Context) -> Self {
         Worker {
-            worker_receiver,
-            player_sender,
-            live_sender,
-            queue,
+            worker_receiver: context.worker_channel.1.clone(),
+            player_sender: context.player_channel.0.clone(),
+            queue: context.queue.clone(),
+            status: context.status.clone(),
+            events: context.events.clone(),
+            stats: context.stats.clone(),
+            history: context.history.clone(),
+            requests: context.requests.clone(),
+            config: context.config.clone(),
+            last_request: RefCell::from(HashMap::new()),
+            last_id: Cell::new(0),
         }
     }
 
@@ -50,18 +67,37 @@ impl Worker {
         match cmd {
             WorkerCommand::Stop => self.stop(),
             WorkerCommand::Next => self.next(),
-            WorkerCommand::PlayNow { kfile } => self.play_now(kfile),
+            WorkerCommand::PlayNow { entry } => self.play_now(entry),
             WorkerCommand::ClearQueue => self.clear_queue(),
-            WorkerCommand::AddQueue { kfile } => self.add_queue(kfile),
+            WorkerCommand::Pause { paused } => self.adjust(PlayerCommand::Pause { paused }),
+            WorkerCommand::SetVolume { volume } => self.adjust(PlayerCommand::Volume { volume }),
+            WorkerCommand::SetKey { key } => self.adjust(PlayerCommand::Key { key }),
+            WorkerCommand::MoveQueue { from, to } => self.move_queue(from, to),
+            WorkerCommand::AddQueue {
+                entry,
+                approved,
+                force,
+                reply,
+            } => {
+                let _ = reply.send(self.add_queue(entry, approved, force));
+            }
+            WorkerCommand::ApproveRequest { id } => self.approve_request(id),
+            WorkerCommand::RejectRequest { id } => self.reject_request(id),
+            WorkerCommand::EditRequest { id, singer } => self.edit_request(id, singer),
+            WorkerCommand::CancelEntry { id, guest, reply } => {
+                let _ = reply.send(self.cancel_entry(id, guest));
+            }
         }
     }
 
+    fn adjust(&self, cmd: PlayerCommand) {
+        self.player_sender.send(cmd).unwrap();
+    }
+
+    //Player discards a Stop received while idle, so it's always safe to send
     fn stop(&self) {
         self.clear_queue();
-
-        if self.live_sender.is_empty() {
-            self.live_sender.send(LiveCommand::Stop).unwrap();
-        }
+        self.player_sender.send(PlayerCommand::Stop).unwrap();
     }
 
     fn next(&self) {
@@ -71,13 +107,15 @@ impl Worker {
             return;
         }
         drop(queue);
-        self.live_sender.send(LiveCommand::Stop).unwrap();
+        self.player_sender.send(PlayerCommand::Stop).unwrap();
     }
 
-    fn play_now(&self, kfile: Kfile) {
-        self.live_sender.send(LiveCommand::Stop).unwrap();
+    //Player replaces the current song itself, so no Stop is needed
+    fn play_now(&self, mut entry: QueueEntry) {
+        self.assign_id(&mut entry);
+        self.record_queued(&entry);
         self.player_sender
-            .send(PlayerCommand::Play { kfile })
+            .send(PlayerCommand::Play { entry })
             .unwrap();
     }
 
@@ -85,11 +123,545 @@ impl Worker {
         let mut queue = self.queue.lock().unwrap();
         queue.clear();
         drop(queue);
+        self.queue_changed();
+    }
+
+    //Positions past the end of the queue are ignored, the queue may have
+    //moved on since the request was made
+    fn move_queue(&self, from: usize, to: usize) {
+        let mut queue = self.queue.lock().unwrap();
+        if from >= queue.len() || to >= queue.len() || from == to {
+            return;
+        }
+        let entry = queue.remove(from);
+        queue.insert(to, entry);
+        drop(queue);
+        self.queue_changed();
+    }
+
+    //Requests waiting for approval count as repeats & towards the limits, as
+    //they'll be queued once approved. Songs the KJ adds skip the limits &
+    //approval, they are often for other singers
+    fn add_queue(
+        &self,
+        mut entry: QueueEntry,
+        approved: bool,
+        force: bool,
+    ) -> Result<Requested, QueueError> {
+        let mut waiting = self.queue.lock().unwrap().clone();
+        waiting.extend(
+            self.requests
+                .lock()
+                .unwrap()
+                .pending()
+                .map(|request| request.entry.clone()),
+        );
+        if !force {
+            self.check_repeat(&entry, &waiting)?;
+        }
+        if !approved {
+            self.check_limits(&entry, &waiting)?;
+            if let Some(requester) = requester(&entry) {
+                self.last_request
+                    .borrow_mut()
+                    .insert(requester, Instant::now());
+            }
+        }
+        self.assign_id(&mut entry);
+
+        if self.config.approval_mode && !approved {
+            let id = self.requests.lock().unwrap().add(entry, now());
+            self.requests_changed();
+            return Ok(Requested::Pending { id });
+        }
+
+        self.enqueue(entry);
+        Ok(Requested::Queued)
     }
 
-    fn add_queue(&self, kfile: Kfile) {
+    fn enqueue(&self, entry: QueueEntry) {
+        self.record_queued(&entry);
         let mut queue = self.queue.lock().unwrap();
-        queue.push(kfile);
+        queue.push(entry);
         drop(queue);
+        self.queue_changed();
+        self.player_sender.send(PlayerCommand::CheckQueue).unwrap();
+    }
+
+    //Approved songs skip the limits, they were checked when requested
+    fn approve_request(&self, id: u64) {
+        let entry = self.requests.lock().unwrap().approve(id);
+        if let Some(entry) = entry {
+            self.requests_changed();
+            self.enqueue(entry);
+        }
+    }
+
+    fn reject_request(&self, id: u64) {
+        let rejected = self.requests.lock().unwrap().reject(id);
+        if rejected {
+            self.requests_changed();
+        }
+    }
+
+    fn edit_request(&self, id: u64, singer: Option<String>) {
+        let edited = self.requests.lock().unwrap().edit(id, singer.clone());
+        if edited {
+            self.requests_changed();
+            return;
+        }
+
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(entry) = queue.iter_mut().find(|entry| entry.id == id) {
+            entry.singer = singer;
+            drop(queue);
+            self.queue_changed();
+        }
+    }
+
+    fn cancel_entry(&self, id: u64, guest: Option<u64>) -> bool {
+        let allowed = |entry: &QueueEntry| guest.is_none() || entry.guest == guest;
+
+        let mut requests = self.requests.lock().unwrap();
+        let pending = requests.pending().find(|request| request.id == id);
+        if pending.map(|request| allowed(&request.entry)) == Some(true) {
+            requests.cancel(id);
+            drop(requests);
+            self.requests_changed();
+            return true;
+        }
+        drop(requests);
+
+        let mut queue = self.queue.lock().unwrap();
+        match queue
+            .iter()
+            .position(|entry| entry.id == id && allowed(entry))
+        {
+            Some(index) => {
+                queue.remove(index);
+                drop(queue);
+                self.queue_changed();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn assign_id(&self, entry: &mut QueueEntry) {
+        self.last_id.set(self.last_id.get() + 1);
+        entry.id = self.last_id.get();
+    }
+
+    //Song is waiting, playing, or was played within the configured repeat window
+    fn check_repeat(&self, entry: &QueueEntry, waiting: &[QueueEntry]) -> Result<(), QueueError> {
+        let status = self.status.lock().unwrap().clone();
+        let repeat = find_repeat(
+            &entry.kfile,
+            waiting,
+            &status,
+            self.history.lock().unwrap().entries(),
+            self.config.repeat_window,
+            now(),
+        );
+        match repeat {
+            Some(repeat) => Err(QueueError::Repeat(repeat)),
+            None => Ok(()),
+        }
+    }
+
+    //Limits of 0 are disabled. Per singer limits apply to each requester, see
+    //requester()
+    fn check_limits(&self, entry: &QueueEntry, queue: &[QueueEntry]) -> Result<(), QueueError> {
+        let config = &self.config;
+        if config.queue_limit > 0 && queue.len() >= config.queue_limit {
+            return Err(QueueError::QueueFull {
+                limit: config.queue_limit,
+            });
+        }
+
+        let who = match requester(entry) {
+            Some(who) => who,
+            None => return Ok(()),
+        };
+
+        let pending = queue
+            .iter()
+            .filter(|e| requester(e).as_ref() == Some(&who))
+            .count();
+        if config.singer_limit > 0 && pending >= config.singer_limit {
+            return Err(QueueError::SingerLimit {
+                limit: config.singer_limit,
+            });
+        }
+
+        let cooldown = Duration::from_secs(config.request_cooldown);
+        if let Some(last) = self.last_request.borrow().get(&who) {
+            let elapsed = last.elapsed();
+            if elapsed < cooldown {
+                let wait = cooldown - elapsed;
+                return Err(QueueError::Cooldown {
+                    wait: wait.as_secs() + u64::from(wait.subsec_nanos() > 0),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    //Playing now counts as a request too
+    fn record_queued(&self, entry: &QueueEntry) {
+        if let Err(e) = self.stats.lock().unwrap().record_queued(entry.kfile.id()) {
+            error!("Failed to record stats: {}", e);
+        }
+    }
+
+    fn requests_changed(&self) {
+        let requests = self.requests.lock().unwrap().all().to_vec();
+        self.events.lock().unwrap().send(&Event::Requests(requests));
+    }
+
+    fn queue_changed(&self) {
+        let queue = self.queue.lock().unwrap().clone();
+        let status = self.status.lock().unwrap().clone();
+        let event = Event::Queue { queue, status };
+        self.events.lock().unwrap().send(&event);
+    }
+}
+
+//Who limits are counted against. Songs from the web UI belong to the guest's
+//session, so changing or leaving out the singer name doesn't get around them.
+//Other songs fall back to the singer name, ignoring case
+fn requester(entry: &QueueEntry) -> Option<String> {
+    match (entry.guest, &entry.singer) {
+        (Some(guest), _) => Some(format!("guest {}", guest)),
+        (None, Some(singer)) => Some(format!("singer {}", singer.to_lowercase())),
+        (None, None) => None,
+    }
+}
+
+//Unix time in seconds
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::bounded;
+    use futures::{Future, Stream};
+    use karaoke::{
+        collection::{Collection, Kfile},
+        history::History,
+        queue::Repeat,
+        stats::Stats,
+    };
+
+    #[test]
+    fn test_add_and_clear_queue() {
+        let context = Context::new(
+            Config::default(),
+            Collection::default(),
+            History::new(),
+            Stats::new(),
+        );
+        let worker = Worker::new(&context);
+        let receiver = context.events.lock().unwrap().new_client();
+
+        let (reply, result) = bounded(1);
+        worker.process_cmd(WorkerCommand::AddQueue {
+            entry: QueueEntry::default(),
+            approved: false,
+            force: false,
+            reply,
+        });
+        assert_eq!(result.recv().unwrap(), Ok(Requested::Queued));
+        assert_eq!(context.queue.lock().unwrap().len(), 1);
+        worker.process_cmd(WorkerCommand::ClearQueue);
+        assert!(context.queue.lock().unwrap().is_empty());
+
+        let events = receiver.take(2).collect().wait().unwrap();
+        let lengths: Vec<usize> = events
+            .into_iter()
+            .map(|event| match event {
+                Event::Queue { queue, .. } => queue.len(),
+                _ => panic!("Expected queue event"),
+            })
+            .collect();
+        assert_eq!(lengths, vec![1, 0]);
+    }
+
+    #[test]
+    fn test_play_now_sends_single_command() {
+        let context = Context::new(
+            Config::default(),
+            Collection::default(),
+            History::new(),
+            Stats::new(),
+        );
+        let worker = Worker::new(&context);
+
+        //Repeated requests are all delivered, none block
+        for _ in 0..10 {
+            worker.process_cmd(WorkerCommand::PlayNow {
+                entry: QueueEntry::default(),
+            });
+        }
+        assert_eq!(context.player_channel.1.len(), 10);
+        assert!(context
+            .player_channel
+            .1
+            .try_iter()
+            .all(|cmd| cmd != PlayerCommand::Stop));
+        assert_eq!(
+            context
+                .stats
+                .lock()
+                .unwrap()
+                .get(Kfile::default().id())
+                .queued,
+            10
+        );
+    }
+
+    #[test]
+    fn test_move_queue() {
+        let context = Context::new(
+            Config::default(),
+            Collection::default(),
+            History::new(),
+            Stats::new(),
+        );
+        let worker = Worker::new(&context);
+        for singer in &["Alice", "Bob", "Carol"] {
+            worker.enqueue(QueueEntry::new(Kfile::default(), Some(singer.to_string())));
+        }
+
+        worker.process_cmd(WorkerCommand::MoveQueue { from: 2, to: 0 });
+        worker.process_cmd(WorkerCommand::MoveQueue { from: 5, to: 0 });
+        let singers: Vec<String> = context
+            .queue
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|entry| entry.singer.clone())
+            .collect();
+        assert_eq!(singers, vec!["Carol", "Alice", "Bob"]);
+    }
+
+    #[test]
+    fn test_queue_limits() {
+        let config = Config {
+            singer_limit: 1,
+            queue_limit: 3,
+            request_cooldown: 60,
+            repeat_window: 0,
+            ..Config::default()
+        };
+        let context = Context::new(config, Collection::default(), History::new(), Stats::new());
+        let worker = Worker::new(&context);
+        let entry = |singer: &str| QueueEntry::new(Kfile::default(), Some(singer.to_string()));
+
+        assert_eq!(
+            worker.add_queue(entry("Alice"), false, false),
+            Ok(Requested::Queued)
+        );
+        assert_eq!(
+            worker.add_queue(entry("alice"), false, false),
+            Err(QueueError::SingerLimit { limit: 1 })
+        );
+        assert_eq!(
+            worker.add_queue(entry("Bob"), false, false),
+            Ok(Requested::Queued)
+        );
+        assert_eq!(
+            worker.add_queue(QueueEntry::default(), false, false),
+            Ok(Requested::Queued)
+        );
+        assert_eq!(
+            worker.add_queue(entry("Carol"), false, false),
+            Err(QueueError::QueueFull { limit: 3 })
+        );
+
+        //Alice's song has played, but she requested too recently
+        context.queue.lock().unwrap().remove(0);
+        match worker.add_queue(entry("Alice"), false, false) {
+            Err(QueueError::Cooldown { wait }) => assert!(wait > 0 && wait <= 60),
+            result => panic!("Expected cooldown, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_guest_limits() {
+        let config = Config {
+            singer_limit: 1,
+            request_cooldown: 60,
+            repeat_window: 0,
+            ..Config::default()
+        };
+        let context = Context::new(config, Collection::default(), History::new(), Stats::new());
+        let worker = Worker::new(&context);
+        let entry = |guest, singer: Option<&str>| QueueEntry {
+            guest: Some(guest),
+            singer: singer.map(String::from),
+            ..QueueEntry::default()
+        };
+
+        //Limits follow the guest, whatever name they sing under
+        assert_eq!(
+            worker.add_queue(entry(1, Some("Alice")), false, false),
+            Ok(Requested::Queued)
+        );
+        assert!(worker.add_queue(entry(1, None), false, false).is_err());
+        assert_eq!(
+            worker.add_queue(entry(1, Some("Bob")), false, false),
+            Err(QueueError::SingerLimit { limit: 1 })
+        );
+        assert_eq!(
+            worker.add_queue(entry(2, None), false, false),
+            Ok(Requested::Queued)
+        );
+
+        context.queue.lock().unwrap().remove(0);
+        match worker.add_queue(entry(1, Some("Carol")), false, false) {
+            Err(QueueError::Cooldown { .. }) => {}
+            result => panic!("Expected cooldown, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_admin_skips_limits() {
+        let config = Config {
+            singer_limit: 1,
+            request_cooldown: 60,
+            repeat_window: 0,
+            ..Config::default()
+        };
+        let context = Context::new(config, Collection::default(), History::new(), Stats::new());
+        let worker = Worker::new(&context);
+        let entry = |singer: &str| QueueEntry {
+            guest: Some(1),
+            singer: Some(singer.to_string()),
+            ..QueueEntry::default()
+        };
+
+        //KJ adds songs for several singers from their own session
+        for singer in &["Alice", "Bob", "Carol"] {
+            assert_eq!(
+                worker.add_queue(entry(singer), true, false),
+                Ok(Requested::Queued)
+            );
+        }
+        assert_eq!(context.queue.lock().unwrap().len(), 3);
+
+        //None of them count towards the KJ's own requests as a guest
+        context.queue.lock().unwrap().clear();
+        assert_eq!(
+            worker.add_queue(entry("Dave"), false, false),
+            Ok(Requested::Queued)
+        );
+    }
+
+    #[test]
+    fn test_repeats() {
+        let config = Config {
+            approval_mode: true,
+            ..Config::default()
+        };
+        let context = Context::new(config, Collection::default(), History::new(), Stats::new());
+        let worker = Worker::new(&context);
+
+        //Waiting for approval counts as queued
+        assert_eq!(
+            worker.add_queue(QueueEntry::default(), false, false),
+            Ok(Requested::Pending { id: 1 })
+        );
+        assert_eq!(
+            worker.add_queue(QueueEntry::default(), true, false),
+            Err(QueueError::Repeat(Repeat::Queued))
+        );
+        assert_eq!(
+            worker.add_queue(QueueEntry::default(), true, true),
+            Ok(Requested::Queued)
+        );
+    }
+
+    #[test]
+    fn test_approval_mode() {
+        let config = Config {
+            approval_mode: true,
+            singer_limit: 1,
+            repeat_window: 0,
+            ..Config::default()
+        };
+        let context = Context::new(config, Collection::default(), History::new(), Stats::new());
+        let worker = Worker::new(&context);
+        let entry = |singer: &str| QueueEntry::new(Kfile::default(), Some(singer.to_string()));
+
+        //Held until approved, and still counted towards the singer's limit
+        assert_eq!(
+            worker.add_queue(entry("Alice"), false, false),
+            Ok(Requested::Pending { id: 1 })
+        );
+        assert!(worker.add_queue(entry("Alice"), false, false).is_err());
+        assert_eq!(
+            worker.add_queue(entry("Bob"), false, false),
+            Ok(Requested::Pending { id: 2 })
+        );
+        assert!(context.queue.lock().unwrap().is_empty());
+
+        //Admins' songs don't wait for approval
+        assert_eq!(
+            worker.add_queue(entry("Carol"), true, false),
+            Ok(Requested::Queued)
+        );
+        context.queue.lock().unwrap().clear();
+
+        worker.process_cmd(WorkerCommand::EditRequest {
+            id: 1,
+            singer: Some(String::from("Alice & Bob")),
+        });
+        worker.process_cmd(WorkerCommand::ApproveRequest { id: 1 });
+        worker.process_cmd(WorkerCommand::RejectRequest { id: 2 });
+
+        let queue = context.queue.lock().unwrap();
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue[0].singer, Some(String::from("Alice & Bob")));
+        assert_eq!(context.requests.lock().unwrap().pending().count(), 0);
+    }
+
+    #[test]
+    fn test_cancel_entry() {
+        let config = Config {
+            approval_mode: true,
+            repeat_window: 0,
+            ..Config::default()
+        };
+        let context = Context::new(config, Collection::default(), History::new(), Stats::new());
+        let worker = Worker::new(&context);
+        let entry = |guest| QueueEntry {
+            guest: Some(guest),
+            ..QueueEntry::default()
+        };
+
+        assert_eq!(
+            worker.add_queue(entry(7), false, false),
+            Ok(Requested::Pending { id: 1 })
+        );
+        assert_eq!(
+            worker.add_queue(entry(8), false, false),
+            Ok(Requested::Pending { id: 2 })
+        );
+        worker.process_cmd(WorkerCommand::ApproveRequest { id: 2 });
+
+        //Guests can only cancel their own, admins can cancel anything
+        assert!(!worker.cancel_entry(1, Some(8)));
+        assert!(worker.cancel_entry(1, Some(7)));
+        assert!(!worker.cancel_entry(1, Some(7)));
+        assert!(!worker.cancel_entry(2, Some(7)));
+        assert!(worker.cancel_entry(2, None));
+        assert!(context.queue.lock().unwrap().is_empty());
+        assert_eq!(context.requests.lock().unwrap().pending().count(), 0);
     }
 }
@@ -0,0 +1,450 @@
+use crossbeam_channel::Sender;
+use futures::Stream;
+use image::GenericImage;
+use karaoke::{
+    channel::PlayerCommand,
+    collection::{Collection, Kfile},
+    config::Config,
+    context::Context,
+    events::Event,
+    headless::{Framebuffer, HeadlessVideo, NullAudio, VirtualClock},
+    history::History,
+    output::{AudioOutput, VideoEvent, VideoOutput},
+    player,
+    stats::Stats,
+    status::PlayerStatus,
+    theme::Backdrop,
+};
+use std::{
+    env,
+    fs::{create_dir_all, remove_dir_all, write, File},
+    path::PathBuf,
+    process,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+//How far the audio clock moves each time the test drives it
+const STEP: Duration = Duration::from_millis(100);
+
+//Fixtures folder, removed again when dropped, even if the test panics
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let path = env::temp_dir().join(format!("karaoke-rs-{}-{}", name, process::id()));
+        create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    //Writes a cdg file, sectors long, whose first sector sets palette color 15
+    //to white and clears the screen to it
+    fn kfile(&self, name: &str, sectors: usize) -> Kfile {
+        let cdg_path = self.0.join(format!("{}.cdg", name));
+        let mut data = vec![0u8; sectors * 96];
+        data[0] = 9;
+        data[1] = 31;
+        data[18] = 0x3f;
+        data[19] = 0x3f;
+        data[24] = 9;
+        data[25] = 1;
+        data[28] = 15;
+        write(&cdg_path, data).unwrap();
+
+        Kfile {
+            mp3_path: cdg_path.with_extension("mp3"),
+            cdg_path,
+            song: String::from(name),
+            ..Kfile::default()
+        }
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.0);
+    }
+}
+
+struct Harness {
+    context: Context,
+    framebuffer: Arc<Mutex<Framebuffer>>,
+    clock: VirtualClock,
+    played: Arc<Mutex<Vec<PathBuf>>>,
+    event_sender: Sender<VideoEvent>,
+    dir: TempDir,
+}
+
+impl Harness {
+    //Player running on its own thread, wired to headless outputs and its own context
+    fn new(name: &str) -> Self {
+        let video = HeadlessVideo::new(640, 480);
+        let audio = NullAudio::new();
+        let framebuffer = video.framebuffer();
+        let event_sender = video.event_sender();
+        let clock = audio.clock();
+        let played = audio.played();
+
+        let context = Context::new(
+            Config::default(),
+            Collection::default(),
+            History::new(),
+            Stats::new(),
+        );
+        player::spawn(&context, move || {
+            (
+                Box::new(video) as Box<dyn VideoOutput>,
+                Box::new(audio) as Box<dyn AudioOutput>,
+            )
+        });
+
+        Harness {
+            context,
+            framebuffer,
+            clock,
+            played,
+            event_sender,
+            dir: TempDir::new(name),
+        }
+    }
+
+    fn play(&self, kfile: &Kfile) {
+        let entry = kfile.clone().into();
+        let sender = &self.context.player_channel.0;
+        sender.send(PlayerCommand::Play { entry }).unwrap();
+    }
+
+    //Moves the audio clock on until done, giving the player time to catch up
+    //between steps
+    fn drive_until<F: Fn(&Harness) -> bool>(&self, done: F) {
+        for _ in 0..5000 {
+            if done(self) {
+                return;
+            }
+            self.clock.advance(STEP);
+            thread::sleep(Duration::from_millis(2));
+        }
+        panic!("Timed out waiting for the player");
+    }
+
+    //Waits for done without moving the audio clock
+    fn wait_until<F: Fn(&Harness) -> bool>(&self, done: F) {
+        for _ in 0..5000 {
+            if done(self) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(2));
+        }
+        panic!("Timed out waiting for the player");
+    }
+
+    fn songs_played(&self) -> usize {
+        self.context.history.lock().unwrap().entries().len()
+    }
+
+    fn is_playing(&self) -> bool {
+        self.context.status.lock().unwrap().is_playing()
+    }
+
+    fn status(&self) -> PlayerStatus {
+        self.context.status.lock().unwrap().clone()
+    }
+}
+
+#[test]
+fn test_play_song_renders_in_sync() {
+    let harness = Harness::new("player_sync");
+    let kfile = harness.dir.kfile("sync", 150);
+    harness.play(&kfile);
+    harness.wait_until(|harness| harness.is_playing());
+
+    //Lyrics are offset by 20 sectors, nothing is drawn before then
+    harness.clock.advance(Duration::from_millis(19 * 1000 / 75));
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(harness.framebuffer.lock().unwrap().frames, 0);
+    harness.drive_until(|harness| harness.framebuffer.lock().unwrap().frames > 0);
+
+    //Song only ends once all 150 sectors (+20 sector offset) have elapsed
+    harness.drive_until(|harness| harness.songs_played() == 1);
+    assert!(harness.clock.elapsed() >= Duration::from_millis(170 * 1000 / 75));
+    assert_eq!(
+        *harness.played.lock().unwrap(),
+        vec![kfile.mp3_path.clone()]
+    );
+    harness.wait_until(|harness| !harness.is_playing());
+
+    //Only the first sector draws anything, later frames reuse its upload
+    let framebuffer = harness.framebuffer.lock().unwrap();
+    assert!(framebuffer.frames > 1);
+    assert_eq!(framebuffer.cdg_uploads, 1);
+
+    //Played to the end, so recorded as not skipped
+    let history = harness.context.history.lock().unwrap();
+    assert!(!history.entries()[0].skipped);
+    let stats = harness.context.stats.lock().unwrap().get(kfile.id());
+    assert_eq!((stats.completed, stats.skipped), (1, 0));
+}
+
+#[test]
+fn test_headless_draws_cdg_frame() {
+    let dir = TempDir::new("player_frame");
+    let kfile = dir.kfile("frame", 1);
+    let mut cdg_interp = cdg_renderer::CdgInterpreter::new();
+    let mut scsi = cdg::SubchannelStreamIter::new(File::open(&kfile.cdg_path).unwrap());
+    for cmd in scsi.next().unwrap() {
+        cdg_interp.handle_cmd(cmd);
+    }
+    let mut cdg_image = image::RgbaImage::new(300, 216);
+    cdg_image.copy_from(&cdg_interp, 0, 0);
+
+    //Cdg frame is centered over the background color
+    let mut video = HeadlessVideo::new(640, 480);
+    let framebuffer = video.framebuffer();
+    video
+        .draw_frame(Backdrop::Color((0.0, 0.0, 0.0, 1.0)), &cdg_image, true)
+        .unwrap();
+    {
+        let framebuffer = framebuffer.lock().unwrap();
+        assert_eq!(
+            framebuffer.image.get_pixel(320, 240).data,
+            [255, 255, 255, 255]
+        );
+        assert_eq!(framebuffer.image.get_pixel(0, 0).data, [0, 0, 0, 255]);
+    }
+
+    //Unchanged frames keep drawing the last copied cdg image
+    video
+        .draw_frame(
+            Backdrop::Color((0.0, 0.0, 0.0, 1.0)),
+            &image::RgbaImage::new(300, 216),
+            false,
+        )
+        .unwrap();
+    let framebuffer = framebuffer.lock().unwrap();
+    assert_eq!(
+        framebuffer.image.get_pixel(320, 240).data,
+        [255, 255, 255, 255]
+    );
+    assert_eq!((framebuffer.frames, framebuffer.cdg_uploads), (2, 1));
+}
+
+#[test]
+fn test_stop_command_ends_song() {
+    let harness = Harness::new("player_stop");
+    let kfile = harness.dir.kfile("stop", 75 * 60);
+    harness.play(&kfile);
+    harness.drive_until(|harness| harness.framebuffer.lock().unwrap().frames > 0);
+
+    let sender = &harness.context.player_channel.0;
+    sender.send(PlayerCommand::Stop).unwrap();
+    harness.wait_until(|harness| harness.songs_played() == 1);
+    assert!(harness.clock.elapsed() < Duration::from_secs(60));
+    harness.wait_until(|harness| !harness.is_playing());
+
+    let history = harness.context.history.lock().unwrap();
+    assert!(history.entries()[0].skipped);
+    assert!(history.entries()[0].played < 60_000);
+}
+
+#[test]
+fn test_stop_right_after_play() {
+    let harness = Harness::new("player_play_stop");
+    let kfile = harness.dir.kfile("play_stop", 75 * 60);
+
+    //Stop may reach the player before the song starts, it still applies to it
+    harness.play(&kfile);
+    let sender = &harness.context.player_channel.0;
+    sender.send(PlayerCommand::Stop).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    assert!(!harness.is_playing());
+
+    //Nothing left over to stop the next song
+    harness.play(&kfile);
+    harness.wait_until(|harness| harness.is_playing());
+    thread::sleep(Duration::from_millis(100));
+    assert!(harness.is_playing());
+}
+
+#[test]
+fn test_stop_after_song_ended() {
+    let harness = Harness::new("player_late_stop");
+    let first = harness.dir.kfile("late_stop_1", 75);
+    let second = harness.dir.kfile("late_stop_2", 75 * 60);
+    harness.play(&first);
+    harness.wait_until(|harness| harness.is_playing());
+
+    //Stop meant for the first song, which ends before the player sees it
+    harness.context.queue.lock().unwrap().push(second.into());
+    harness.clock.advance(Duration::from_secs(10));
+    let sender = &harness.context.player_channel.0;
+    sender.send(PlayerCommand::Stop).unwrap();
+
+    harness.wait_until(|harness| harness.songs_played() == 1 && harness.is_playing());
+    thread::sleep(Duration::from_millis(200));
+    assert!(harness.is_playing());
+    assert_eq!(harness.songs_played(), 1);
+}
+
+#[test]
+fn test_play_command_replaces_song() {
+    let harness = Harness::new("player_replace");
+    let first = harness.dir.kfile("replace_1", 75 * 60);
+    let second = harness.dir.kfile("replace_2", 75);
+
+    harness.play(&first);
+    harness.wait_until(|harness| harness.is_playing());
+    harness.play(&second);
+    harness.drive_until(|harness| harness.songs_played() == 2);
+
+    assert_eq!(
+        *harness.played.lock().unwrap(),
+        vec![first.mp3_path.clone(), second.mp3_path.clone()]
+    );
+    let history = harness.context.history.lock().unwrap();
+    assert!(history.entries()[0].skipped);
+    assert!(!history.entries()[1].skipped);
+}
+
+#[test]
+fn test_check_queue_advances() {
+    let harness = Harness::new("player_queue");
+    let first = harness.dir.kfile("queue_1", 75);
+    let second = harness.dir.kfile("queue_2", 75);
+    harness
+        .context
+        .queue
+        .lock()
+        .unwrap()
+        .extend(vec![first.clone().into(), second.clone().into()]);
+
+    harness
+        .context
+        .player_channel
+        .0
+        .send(PlayerCommand::CheckQueue)
+        .unwrap();
+    harness.drive_until(|harness| harness.songs_played() == 2);
+    harness.wait_until(|harness| !harness.is_playing());
+
+    assert!(harness.context.queue.lock().unwrap().is_empty());
+    assert_eq!(
+        *harness.played.lock().unwrap(),
+        vec![first.mp3_path.clone(), second.mp3_path.clone()]
+    );
+}
+
+#[test]
+fn test_bad_song_is_skipped() {
+    let harness = Harness::new("player_bad");
+    let receiver = harness.context.events.lock().unwrap().new_client();
+    let good = harness.dir.kfile("good", 75);
+    let bad = Kfile {
+        cdg_path: harness.dir.0.join("missing.cdg"),
+        song: String::from("Missing"),
+        ..Kfile::default()
+    };
+    harness
+        .context
+        .queue
+        .lock()
+        .unwrap()
+        .extend(vec![bad.into(), good.clone().into()]);
+
+    harness
+        .context
+        .player_channel
+        .0
+        .send(PlayerCommand::CheckQueue)
+        .unwrap();
+    //Songs that fail to open never start, so aren't in history
+    harness.drive_until(|harness| harness.songs_played() == 1);
+    assert!(harness.context.queue.lock().unwrap().is_empty());
+    assert_eq!(*harness.played.lock().unwrap(), vec![good.mp3_path.clone()]);
+
+    let error = receiver
+        .wait()
+        .filter_map(Result::ok)
+        .find(|event| matches!(event, Event::PlaybackError { .. }));
+    match error {
+        Some(Event::PlaybackError { song, .. }) => assert!(song.ends_with("Missing")),
+        _ => panic!("Expected playback error event"),
+    }
+}
+
+#[test]
+fn test_failed_song_shows_notice() {
+    let harness = Harness::new("player_notice");
+    harness.play(&Kfile {
+        cdg_path: harness.dir.0.join("missing.cdg"),
+        ..Kfile::default()
+    });
+    harness.wait_until(|harness| harness.framebuffer.lock().unwrap().notice.is_some());
+
+    let framebuffer = harness.framebuffer.lock().unwrap();
+    assert!(framebuffer.notice.as_ref().unwrap().starts_with("Skipped"));
+    assert!(harness.played.lock().unwrap().is_empty());
+}
+
+#[test]
+fn test_escape_key_skips_song() {
+    let harness = Harness::new("player_escape");
+    let kfile = harness.dir.kfile("escape", 75 * 60);
+
+    //Clock isn't driven, so the song is still before its first frame
+    harness.play(&kfile);
+    harness.wait_until(|harness| harness.is_playing());
+    harness.event_sender.send(VideoEvent::Skip).unwrap();
+    harness.wait_until(|harness| harness.songs_played() == 1);
+    harness.wait_until(|harness| harness.framebuffer.lock().unwrap().idle_frames == 2);
+
+    assert_eq!(harness.framebuffer.lock().unwrap().frames, 0);
+    assert!(harness.context.history.lock().unwrap().entries()[0].skipped);
+}
+
+#[test]
+fn test_pause_holds_position() {
+    let harness = Harness::new("player_pause");
+    let kfile = harness.dir.kfile("pause", 75 * 60);
+    harness.play(&kfile);
+    harness.drive_until(|harness| harness.status().position > 0);
+
+    let sender = &harness.context.player_channel.0;
+    sender.send(PlayerCommand::Pause { paused: true }).unwrap();
+    harness.wait_until(|harness| harness.status().paused);
+    thread::sleep(Duration::from_millis(50));
+    let position = harness.status().position;
+    harness.clock.advance(Duration::from_secs(10));
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(harness.status().position, position);
+
+    //Picks up where it left off
+    sender.send(PlayerCommand::Pause { paused: false }).unwrap();
+    harness.wait_until(|harness| !harness.status().paused);
+    harness.drive_until(|harness| harness.status().position > position);
+    assert!(harness.status().position < position + 10_000);
+    assert!(harness.is_playing());
+}
+
+#[test]
+fn test_key_and_volume_carry_over() {
+    let harness = Harness::new("player_adjust");
+    let kfile = harness.dir.kfile("adjust", 75);
+
+    //Set while idle, clamped to what the player supports
+    let sender = &harness.context.player_channel.0;
+    sender.send(PlayerCommand::Key { key: 20 }).unwrap();
+    sender.send(PlayerCommand::Volume { volume: 200 }).unwrap();
+    sender.send(PlayerCommand::Pause { paused: true }).unwrap();
+    harness.wait_until(|harness| harness.status().key == player::MAX_KEY);
+    harness.wait_until(|harness| harness.status().volume == 100);
+    assert!(!harness.status().paused);
+
+    sender.send(PlayerCommand::Volume { volume: 40 }).unwrap();
+    harness.play(&kfile);
+    harness.wait_until(|harness| harness.is_playing());
+    let status = harness.status();
+    assert_eq!((status.key, status.volume), (player::MAX_KEY, 40));
+    harness.drive_until(|harness| harness.songs_played() == 1);
+    assert_eq!(harness.status().volume, 40);
+}